@@ -1,4 +1,4 @@
-use std::{path::PathBuf, net::SocketAddr};
+use std::{path::PathBuf, net::SocketAddr, process, time::SystemTime};
 use structopt::StructOpt;
 use vru_session::handshake::Identity;
 
@@ -6,6 +6,12 @@ use vru_session::handshake::Identity;
 pub struct Args {
     #[structopt(long)]
     path: PathBuf,
+    #[structopt(
+        long,
+        default_value = "ctrl.sock",
+        help = "the control socket, relative to the node directory, as `control.socket` in the config"
+    )]
+    socket: PathBuf,
     #[structopt(subcommand)]
     cmd: Cmd,
 }
@@ -14,27 +20,176 @@ pub struct Args {
 pub enum Cmd {
     Connect { peer: Identity, address: SocketAddr },
     SendText { peer: Identity, text: String },
+    Disconnect { peer: Identity },
+    #[structopt(about = "print the short authentication string of the session, \
+                         compare it with the peer's one out of band")]
+    AuthString { peer: Identity },
+    #[structopt(about = "print the identity of the node")]
+    Identity,
+    #[structopt(about = "print established sessions")]
+    ListPeers,
+    Status,
+    #[structopt(about = "remember the peer, the node connects to trusted peers on startup")]
+    AddPeer {
+        peer: Identity,
+        #[structopt(long)]
+        address: Option<SocketAddr>,
+        #[structopt(long)]
+        trusted: bool,
+    },
+    #[structopt(about = "print peers remembered by the node")]
+    KnownPeers,
+    RemovePeer { peer: Identity },
+    #[structopt(about = "encrypt the seed with the new passphrase, \
+                         reads VRU_PASSPHRASE and VRU_NEW_PASSPHRASE or asks for them")]
+    ChangePassphrase,
+    #[structopt(about = "print events of the node until it stops")]
+    Watch,
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(2)
+}
+
+fn ago(time: SystemTime) -> String {
+    match time.elapsed() {
+        Ok(elapsed) => format!("{}s ago", elapsed.as_secs()),
+        Err(_) => "just now".to_string(),
+    }
+}
+
+fn print_reply(reply: vru_session::Reply<SocketAddr>) {
+    use vru_session::Reply;
+
+    match reply {
+        Reply::Done => println!("ok"),
+        Reply::Identity(identity) => println!("{}", identity),
+        Reply::AuthString(auth_string) => println!("{}", auth_string),
+        Reply::Peers(peers) => {
+            for peer in peers {
+                println!(
+                    "{} {} {} {}, handshake {}, in: {} bytes, out: {} bytes",
+                    peer.identity,
+                    peer.transport,
+                    if peer.incoming { "from" } else { "to" },
+                    peer.address,
+                    ago(peer.handshake_time),
+                    peer.bytes_in,
+                    peer.bytes_out,
+                );
+            }
+        },
+        Reply::Status(status) => {
+            println!("identity: {}", status.identity);
+            println!("address: {}", status.address);
+            println!("transport: {}", status.transport);
+            println!("peers: {}", status.peers);
+            println!("started: {}", ago(status.start_time));
+        },
+    }
+}
+
+fn print_known(peers: Vec<vru_node::control::KnownPeer>) {
+    for peer in peers {
+        let addresses = peer
+            .addresses
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        println!(
+            "{}{} [{}], first seen {}, last handshake {}",
+            peer.identity,
+            if peer.trusted { " trusted" } else { "" },
+            addresses.join(", "),
+            ago(peer.first_seen),
+            peer.last_seen.map(ago).unwrap_or_else(|| "never".to_string()),
+        );
+    }
+}
+
+fn passphrase(var: &str, prompt: &str) -> String {
+    use std::env;
+
+    env::var(var).or_else(|_| rpassword::read_password_from_tty(Some(prompt)))
+        .unwrap_or_else(|error| fail(format!("cannot read passphrase, error: {}", error)))
 }
 
 fn main() {
     use std::os::unix::net::UnixStream;
     use vru_session::Command;
+    use vru_node::control::{self, Request, Body, AddressBook, Response, Reply};
 
-    let Args { path, cmd } = StructOpt::from_args();
-    let command = match cmd {
-        Cmd::Connect { peer, address } => Command::Connect {
+    let Args { path, socket, cmd } = StructOpt::from_args();
+    let body = match cmd {
+        Cmd::Connect { peer, address } => Body::Command(Command::Connect {
             peer_pi: peer,
             address,
-        },
-        Cmd::SendText { peer, text } => Command::Local {
+        }),
+        Cmd::SendText { peer, text } => Body::Command(Command::Local {
             destination: peer,
             command: text.into_bytes(),
+        }),
+        Cmd::Disconnect { peer } => Body::Command(Command::Disconnect { peer_pi: peer }),
+        Cmd::AuthString { peer } => Body::Command(Command::AuthString { peer_pi: peer }),
+        Cmd::Identity => Body::Command(Command::Identity),
+        Cmd::ListPeers => Body::Command(Command::ListPeers),
+        Cmd::Status => Body::Command(Command::Status),
+        Cmd::AddPeer {
+            peer,
+            address,
+            trusted,
+        } => Body::AddressBook(AddressBook::Add {
+            peer,
+            address,
+            trusted,
+        }),
+        Cmd::KnownPeers => Body::AddressBook(AddressBook::List),
+        Cmd::RemovePeer { peer } => Body::AddressBook(AddressBook::Remove { peer }),
+        Cmd::ChangePassphrase => {
+            let old = passphrase("VRU_PASSPHRASE", "current passphrase, empty if there is none: ");
+            let new = passphrase("VRU_NEW_PASSPHRASE", "new passphrase: ");
+            if new.is_empty() {
+                fail("the new passphrase is empty".to_string());
+            }
+            Body::ChangePassphrase {
+                old: Some(old).filter(|old| !old.is_empty()),
+                new,
+            }
         },
+        Cmd::Watch => Body::Subscribe,
     };
 
-    let path = path.join("ctrl.sock");
+    let path = path.join(socket);
     let ctrl = UnixStream::connect(&path)
-        .unwrap_or_else(|error| panic!("cannot connect to: {:?}, error: {:?}", path, error));
-    bincode::serialize_into(ctrl, &command)
-        .unwrap_or_else(|error| panic!("cannot send command to: {:?}, error: {:?}", path, error));
+        .unwrap_or_else(|error| fail(format!("cannot connect to: {:?}, error: {}", path, error)));
+    let request = Request::new(1, body);
+    control::write(&ctrl, &request)
+        .unwrap_or_else(|error| fail(format!("cannot send command to: {:?}, error: {}", path, error)));
+    let mut subscribed = false;
+    loop {
+        let response = match control::read::<_, Response>(&ctrl) {
+            Ok(Some(response)) if response.id == request.id => response,
+            Ok(Some(response)) => fail(format!("unexpected response id: {}", response.id)),
+            Ok(None) if subscribed => break,
+            Ok(None) => fail("the node closed the connection".to_string()),
+            Err(error) => fail(format!("cannot read response, error: {}", error)),
+        };
+        match response.result {
+            Ok(Reply::Node(reply)) => {
+                print_reply(reply);
+                break;
+            },
+            Ok(Reply::AddressBook(peers)) => {
+                print_known(peers);
+                break;
+            },
+            Ok(Reply::Subscribed) => subscribed = true,
+            Ok(Reply::Event(notification)) => println!("{}", notification),
+            Err(error) => {
+                eprintln!("error: {}", error);
+                process::exit(1);
+            },
+        }
+    }
 }
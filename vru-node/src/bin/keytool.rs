@@ -0,0 +1,190 @@
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+};
+use structopt::StructOpt;
+use rac::{Array, LineValid, generic_array::typenum};
+use vru_session::handshake::PublicKey;
+use vru_node::{database::{Database, DatabaseError}, mnemonic, seal};
+
+// the file passphrase is read from VRU_FILE_PASSPHRASE or from the terminal,
+// the node database is encrypted with VRU_PASSPHRASE if it is set,
+// an encrypted seed is replaced only by an encrypted one unless `--plaintext`
+#[derive(StructOpt)]
+enum Args {
+    #[structopt(about = "generate a new seed")]
+    Generate {
+        #[structopt(flatten)]
+        backup: Backup,
+    },
+    #[structopt(about = "make a backup of the seed of the node, the node must be stopped")]
+    Export {
+        #[structopt(long, help = "the directory of the node")]
+        path: PathBuf,
+        #[structopt(flatten)]
+        backup: Backup,
+    },
+    #[structopt(about = "restore the seed of the node from the backup, the node must be stopped")]
+    Import {
+        #[structopt(long, help = "the directory of the node")]
+        path: PathBuf,
+        #[structopt(flatten)]
+        restore: Restore,
+        #[structopt(long, help = "replace the existing seed")]
+        replace: bool,
+        #[structopt(long, help = "store the seed unencrypted even if the existing seed is encrypted")]
+        plaintext: bool,
+    },
+    #[structopt(about = "print the identity and the public key of the backup")]
+    Show {
+        #[structopt(flatten)]
+        restore: Restore,
+    },
+}
+
+#[derive(StructOpt)]
+struct Backup {
+    #[structopt(
+        long,
+        required_unless = "mnemonic",
+        help = "write the seed encrypted with a passphrase to the new file"
+    )]
+    file: Option<PathBuf>,
+    #[structopt(long, help = "print the seed as words")]
+    mnemonic: bool,
+}
+
+#[derive(StructOpt)]
+struct Restore {
+    #[structopt(long, required_unless = "mnemonic", help = "read the encrypted file")]
+    file: Option<PathBuf>,
+    #[structopt(long, conflicts_with = "file", help = "read words from stdin")]
+    mnemonic: bool,
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(2)
+}
+
+fn passphrase(var: &str, prompt: &str) -> String {
+    env::var(var)
+        .or_else(|_| rpassword::read_password_from_tty(Some(prompt)))
+        .unwrap_or_else(|error| fail(format!("cannot read passphrase, error: {}", error)))
+}
+
+fn open_database(path: &Path) -> Database {
+    let path = path.join("db");
+    if !path.exists() {
+        fail(format!("there is no database at: {:?}", path));
+    }
+    Database::open(&path)
+        .unwrap_or_else(|error| fail(format!("cannot open database at: {:?}, error: {}", path, error)))
+}
+
+fn print_key(seed: &Array<typenum::U96>) {
+    let (pk, _) = PublicKey::gen(seed);
+    println!("identity: {}", pk.identity());
+    println!("public key: {}", hex::encode(pk.compress().clone_line()));
+}
+
+fn backup(seed: &Array<typenum::U96>, backup: Backup) {
+    if let Some(path) = backup.file {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let passphrase = passphrase("VRU_FILE_PASSPHRASE", "file passphrase: ");
+        if passphrase.is_empty() {
+            fail("the passphrase is empty".to_string());
+        }
+        let sealed = seal::seal(seal::Kdf::default(), passphrase.as_bytes(), seed.as_ref())
+            .unwrap_or_else(|error| fail(format!("cannot encrypt the seed, error: {}", error)));
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&sealed))
+            .unwrap_or_else(|error| fail(format!("cannot write: {:?}, error: {}", path, error)));
+        println!("written: {:?}", path);
+    }
+    if backup.mnemonic {
+        println!("{}", mnemonic::encode(seed));
+    }
+}
+
+fn restore(restore: Restore) -> Array<typenum::U96> {
+    if restore.mnemonic {
+        let mut words = String::new();
+        io::stdin()
+            .read_to_string(&mut words)
+            .unwrap_or_else(|error| fail(format!("cannot read stdin, error: {}", error)));
+        return mnemonic::decode(&words).unwrap_or_else(|error| fail(format!("bad mnemonic: {}", error)));
+    }
+
+    let path = restore.file.expect("the file is required unless the mnemonic");
+    let sealed = fs::read(&path)
+        .unwrap_or_else(|error| fail(format!("cannot read: {:?}, error: {}", path, error)));
+    let passphrase = passphrase("VRU_FILE_PASSPHRASE", "file passphrase: ");
+    let seed = seal::open(passphrase.as_bytes(), &sealed)
+        .unwrap_or_else(|error| fail(format!("cannot decrypt: {:?}, error: {}", path, error)));
+    if seed.len() != 96 {
+        fail(format!("bad seed length: {}", seed.len()));
+    }
+    Array::clone_from_slice(&seed)
+}
+
+fn main() {
+    match StructOpt::from_args() {
+        Args::Generate { backup: b } => {
+            let mut seed = Array::default();
+            rand::Rng::fill(&mut rand::thread_rng(), &mut seed[..]);
+            print_key(&seed);
+            backup(&seed, b);
+        },
+        Args::Export { path, backup: b } => {
+            let db = open_database(&path);
+            let seed = match db.seed(None) {
+                Ok(seed) => seed,
+                Err(DatabaseError::Locked) => {
+                    let passphrase = passphrase("VRU_PASSPHRASE", "node passphrase: ");
+                    db.seed(Some(passphrase.as_bytes()))
+                        .unwrap_or_else(|error| fail(error.to_string()))
+                },
+                Err(error) => fail(error.to_string()),
+            };
+            let seed = seed.unwrap_or_else(|| fail(format!("there is no seed at: {:?}", path)));
+            print_key(&seed);
+            backup(&seed, b);
+        },
+        Args::Import {
+            path,
+            restore: r,
+            replace,
+            plaintext,
+        } => {
+            let seed = restore(r);
+            fs::create_dir_all(&path)
+                .unwrap_or_else(|error| fail(format!("cannot create: {:?}, error: {}", path, error)));
+            let db_path = path.join("db");
+            let db = Database::open(&db_path)
+                .unwrap_or_else(|error| fail(format!("cannot open database at: {:?}, error: {}", db_path, error)));
+            let passphrase = match (env::var("VRU_PASSPHRASE").ok(), db.seed(None)) {
+                (Some(passphrase), _) => Some(passphrase),
+                (None, Err(DatabaseError::Locked)) if replace && !plaintext => {
+                    let passphrase = passphrase("VRU_PASSPHRASE", "new node passphrase: ");
+                    if passphrase.is_empty() {
+                        fail("the passphrase is empty, use --plaintext to store the seed unencrypted".to_string());
+                    }
+                    Some(passphrase)
+                },
+                (None, _) => None,
+            };
+            db.import_seed(&seed, passphrase.as_ref().map(String::as_bytes), replace)
+                .unwrap_or_else(|error| fail(error.to_string()));
+            print_key(&seed);
+        },
+        Args::Show { restore: r } => print_key(&restore(r)),
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use serde::Deserialize;
+use thiserror::Error;
+use vru_session::handshake::Identity;
+
+// the file in the node directory, it is optional
+pub const FILE_NAME: &str = "config.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("cannot read config: {:?}, error: {}", _0, _1)]
+    Read(PathBuf, io::Error),
+    #[error("bad config: {:?}, error: {}", _0, _1)]
+    Parse(PathBuf, toml::de::Error),
+    #[error("bad config: {}", _0)]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportMode {
+    Tcp,
+    Udp,
+    Both,
+}
+
+impl FromStr for TransportMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(TransportMode::Tcp),
+            "udp" => Ok(TransportMode::Udp),
+            "both" => Ok(TransportMode::Both),
+            _ => Err(format!("unknown transport: {}, expected tcp, udp or both", s)),
+        }
+    }
+}
+
+// every field is optional, command line flags override them
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub transport: Option<TransportMode>,
+    pub log_level: Option<String>,
+    pub listen: Listen,
+    pub bootstrap: Vec<Bootstrap>,
+    pub limits: Limits,
+    pub control: Control,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listen {
+    pub tcp: Option<SocketAddr>,
+    pub udp: Option<SocketAddr>,
+}
+
+// the node connects to these peers on startup
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bootstrap {
+    pub peer: String,
+    pub address: SocketAddr,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_incoming: Option<usize>,
+}
+
+// the relative path of the socket is relative to the node directory,
+// the mode is better written in octal, like `0o600`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Control {
+    pub socket: Option<PathBuf>,
+    pub mode: Option<u32>,
+}
+
+// validated config
+#[derive(Debug)]
+pub struct Settings {
+    pub listen: ListenOn,
+    pub log_level: tracing::Level,
+    pub bootstrap: Vec<(Identity, SocketAddr)>,
+    pub max_incoming: Option<usize>,
+    pub control_socket: PathBuf,
+    pub control_mode: u32,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ListenOn {
+    Tcp(SocketAddr),
+    Udp(SocketAddr),
+    Both { tcp: SocketAddr, udp: SocketAddr },
+}
+
+impl Config {
+    // the missing file is the same as the empty one
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|error| ConfigError::Parse(path.to_owned(), error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(error) => Err(ConfigError::Read(path.to_owned(), error)),
+        }
+    }
+
+    pub fn settings(self, directory: &Path) -> Result<Settings, ConfigError> {
+        let invalid = ConfigError::Invalid;
+
+        let listen_tcp = || match self.listen.tcp {
+            Some(address) => Ok(address),
+            None => Err(invalid("no listen address for tcp, set `listen.tcp` or use --port".to_string())),
+        };
+        let listen_udp = || match self.listen.udp {
+            Some(address) => Ok(address),
+            None => Err(invalid("no listen address for udp, set `listen.udp` or use --port".to_string())),
+        };
+        let listen = match self.transport.unwrap_or(TransportMode::Tcp) {
+            TransportMode::Tcp => ListenOn::Tcp(listen_tcp()?),
+            TransportMode::Udp => ListenOn::Udp(listen_udp()?),
+            TransportMode::Both => ListenOn::Both {
+                tcp: listen_tcp()?,
+                udp: listen_udp()?,
+            },
+        };
+
+        let log_level = match &self.log_level {
+            None => tracing::Level::INFO,
+            Some(level) => match level.parse() {
+                Ok(level) => level,
+                Err(_) => {
+                    return Err(invalid(format!(
+                        "unknown log level: {}, expected error, warn, info, debug or trace",
+                        level,
+                    )))
+                },
+            },
+        };
+
+        let mut bootstrap = Vec::with_capacity(self.bootstrap.len());
+        for Bootstrap { peer, address } in &self.bootstrap {
+            let identity = match peer.parse() {
+                Ok(identity) => identity,
+                Err(error) => {
+                    return Err(invalid(format!(
+                        "bad identity of bootstrap peer: {}, error: {}",
+                        peer, error,
+                    )))
+                },
+            };
+            bootstrap.push((identity, *address));
+        }
+
+        if self.limits.max_incoming == Some(0) {
+            return Err(invalid("`limits.max_incoming` should be positive".to_string()));
+        }
+
+        let control_mode = self.control.mode.unwrap_or(0o600);
+        if control_mode > 0o777 {
+            return Err(invalid(format!("bad `control.mode`: {:o}, expected at most 0o777", control_mode)));
+        }
+
+        Ok(Settings {
+            listen,
+            log_level,
+            bootstrap,
+            max_incoming: self.limits.max_incoming,
+            control_socket: directory.join(self.control.socket.unwrap_or_else(|| "ctrl.sock".into())),
+            control_mode,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::{Config, ConfigError, ListenOn, TransportMode};
+
+    #[test]
+    fn full() {
+        let text = r#"
+            transport = "both"
+            log_level = "debug"
+
+            [listen]
+            tcp = "0.0.0.0:8224"
+            udp = "127.0.0.1:8225"
+
+            [[bootstrap]]
+            peer = "AXhEq/scfbxy4+V2bgvrxPi5aewzKmENWvWXYdkKBic7WwO1"
+            address = "10.0.0.1:8224"
+
+            [limits]
+            max_incoming = 16
+
+            [control]
+            socket = "/run/vru/ctrl.sock"
+            mode = 0o660
+        "#;
+        let config = toml::from_str::<Config>(text).unwrap();
+        assert_eq!(config.transport, Some(TransportMode::Both));
+        let settings = config.settings(Path::new("/var/lib/vru")).unwrap();
+        assert_eq!(settings.listen, ListenOn::Both {
+            tcp: ([0, 0, 0, 0], 8224).into(),
+            udp: ([127, 0, 0, 1], 8225).into(),
+        });
+        assert_eq!(settings.log_level, tracing::Level::DEBUG);
+        assert_eq!(settings.bootstrap.len(), 1);
+        assert_eq!(settings.max_incoming, Some(16));
+        assert_eq!(settings.control_socket, Path::new("/run/vru/ctrl.sock"));
+        assert_eq!(settings.control_mode, 0o660);
+    }
+
+    #[test]
+    fn defaults() {
+        let text = "listen.tcp = \"0.0.0.0:8224\"";
+        let settings = toml::from_str::<Config>(text)
+            .unwrap()
+            .settings(Path::new("node"))
+            .unwrap();
+        assert_eq!(settings.listen, ListenOn::Tcp(([0, 0, 0, 0], 8224).into()));
+        assert_eq!(settings.control_socket, Path::new("node/ctrl.sock"));
+        assert_eq!(settings.control_mode, 0o600);
+    }
+
+    #[test]
+    fn invalid() {
+        let error = toml::from_str::<Config>("[listen]\nsctp = \"0.0.0.0:1\"").unwrap_err();
+        assert!(error.to_string().contains("sctp"));
+
+        let settings = |text: &str| toml::from_str::<Config>(text).unwrap().settings(Path::new(""));
+        let message = |text: &str| match settings(text) {
+            Err(ConfigError::Invalid(message)) => message,
+            result => panic!("unexpected: {:?}", result),
+        };
+        assert!(message("transport = \"udp\"\nlisten.tcp = \"0.0.0.0:1\"").contains("listen.udp"));
+        assert!(message("listen.tcp = \"0.0.0.0:1\"\nlog_level = \"loud\"").contains("loud"));
+        assert!(message("listen.tcp = \"0.0.0.0:1\"\nlimits.max_incoming = 0").contains("max_incoming"));
+        assert!(message("listen.tcp = \"0.0.0.0:1\"\ncontrol.mode = 0o1777").contains("1777"));
+        let text = "listen.tcp = \"0.0.0.0:1\"\n[[bootstrap]]\npeer = \"AAAA\"\naddress = \"0.0.0.0:1\"";
+        assert!(message(text).contains("AAAA"));
+        // the checksum is wrong
+        let text = text.replace("AAAA", "AXhEq/scfbxy4+V2bgvrxPi5aewzKmENWvWXYdkKBic7WwO2");
+        assert!(message(&text).contains("checksum"));
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    time::SystemTime,
+};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use thiserror::Error;
+use vru_session::{Command, Event, handshake::{Identity, PublicKey}};
+
+pub type NodeReply = vru_session::Reply<SocketAddr>;
+
+// the version of the control protocol, the daemon rejects requests of another version
+pub const VERSION: u16 = 1;
+
+// the header is the length of the body (4 bytes, big endian) and the version (2 bytes, big endian),
+// the body is the bincode encoded message, it is not decoded if the version does not match
+const HEADER: usize = 6;
+const MAX_MESSAGE: usize = 1 << 20;
+
+#[derive(Debug, Error)]
+pub enum ReadError {
+    #[error("{}", _0)]
+    Io(#[from] io::Error),
+    #[error("unsupported protocol version {}, supported: {}", _0, VERSION)]
+    Version(u16),
+    #[error("message is too big: {}", _0)]
+    TooBig(usize),
+    #[error("malformed message: {}", _0)]
+    Malformed(bincode::Error),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub id: u64,
+    pub body: Body,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Body {
+    Command(Command<SocketAddr>),
+    AddressBook(AddressBook),
+    // `old` is not needed if the seed is not encrypted yet
+    ChangePassphrase {
+        old: Option<String>,
+        new: String,
+    },
+    // the daemon answers `Reply::Subscribed` and then sends `Reply::Event`
+    // with the id of the request until the client closes the connection
+    Subscribe,
+}
+
+// peers known by the node, it connects to trusted peers on startup
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AddressBook {
+    Add {
+        peer: Identity,
+        address: Option<SocketAddr>,
+        trusted: bool,
+    },
+    List,
+    Remove {
+        peer: Identity,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownPeer {
+    pub identity: Identity,
+    // known after the first handshake
+    pub public_key: Option<PublicKey>,
+    // the most recent first
+    pub addresses: Vec<SocketAddr>,
+    pub first_seen: SystemTime,
+    // the time of the last handshake
+    pub last_seen: Option<SystemTime>,
+    pub trusted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub id: u64,
+    pub result: Result<Reply, ErrorReply>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Reply {
+    Node(NodeReply),
+    Subscribed,
+    Event(Notification),
+    // the list of peers, or the added or removed peer
+    AddressBook(Vec<KnownPeer>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Notification {
+    HandshakeDone { peer: Identity, incoming: bool },
+    Message { source: Identity, data: Vec<u8> },
+    Error(String),
+    Disconnected { peer: Identity },
+}
+
+impl Notification {
+    // debug information is not interesting for subscribers
+    pub fn from_event<E, A>(event: &Event<E, A>) -> Option<Self>
+    where
+        E: fmt::Display,
+    {
+        match event {
+            Event::Error(error) => Some(Notification::Error(error.to_string())),
+            Event::DebugInfo(_) => None,
+            Event::Local { source, local } => Some(Notification::Message {
+                source: source.identity(),
+                data: local.clone(),
+            }),
+            Event::HandshakeDone { peer, incoming, .. } => Some(Notification::HandshakeDone {
+                peer: peer.identity(),
+                incoming: *incoming,
+            }),
+            Event::Disconnected { peer } => Some(Notification::Disconnected { peer: peer.clone() }),
+        }
+    }
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Notification::HandshakeDone { peer, incoming: true } => {
+                write!(f, "handshake done, incoming from: {}", peer)
+            },
+            Notification::HandshakeDone { peer, incoming: false } => {
+                write!(f, "handshake done, outgoing to: {}", peer)
+            },
+            Notification::Message { source, data } => {
+                write!(f, "message from: {}, {}", source, String::from_utf8_lossy(data))
+            },
+            Notification::Error(error) => write!(f, "error: {}", error),
+            Notification::Disconnected { peer } => write!(f, "disconnected: {}", peer),
+        }
+    }
+}
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum ErrorReply {
+    #[error("unsupported protocol version {}, supported: {}", requested, supported)]
+    UnsupportedVersion { supported: u16, requested: u16 },
+    #[error("malformed request: {}", _0)]
+    Malformed(String),
+    #[error("already connected, identity: {}", _0)]
+    AlreadyConnected(Identity),
+    #[error("no session, identity: {}", _0)]
+    NoSession(Identity),
+    #[error("unknown peer, identity: {}", _0)]
+    UnknownPeer(Identity),
+    #[error("connection failed: {}, address: {}", reason, address)]
+    ConnectionFailed { address: SocketAddr, reason: String },
+    #[error("{}", _0)]
+    Node(String),
+    #[error("database error: {}", _0)]
+    Database(String),
+}
+
+impl Request {
+    pub fn new(id: u64, body: Body) -> Self {
+        Request { id, body }
+    }
+}
+
+impl Response {
+    pub fn new(id: u64, result: Result<Reply, ErrorReply>) -> Self {
+        Response { id, result }
+    }
+}
+
+impl From<vru_tcp::NodeError> for ErrorReply {
+    fn from(error: vru_tcp::NodeError) -> Self {
+        use vru_tcp::NodeError;
+
+        match error {
+            NodeError::AlreadyConnected(identity) => ErrorReply::AlreadyConnected(identity),
+            NodeError::NoSession(identity) => ErrorReply::NoSession(identity),
+            NodeError::Connect(address, error) => ErrorReply::ConnectionFailed {
+                address,
+                reason: error.to_string(),
+            },
+            error => ErrorReply::Node(error.to_string()),
+        }
+    }
+}
+
+impl From<vru_udp::NodeError> for ErrorReply {
+    fn from(error: vru_udp::NodeError) -> Self {
+        use vru_udp::NodeError;
+
+        match error {
+            NodeError::AlreadyConnected(identity) => ErrorReply::AlreadyConnected(identity),
+            NodeError::NoSession(identity) => ErrorReply::NoSession(identity),
+            NodeError::WriteTo(address, error) => ErrorReply::ConnectionFailed {
+                address,
+                reason: error.to_string(),
+            },
+            error => ErrorReply::Node(error.to_string()),
+        }
+    }
+}
+
+pub fn write<W, T>(mut writer: W, message: &T) -> io::Result<()>
+where
+    W: Write,
+    T: Serialize,
+{
+    let bytes = bincode::serialize(message)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    if bytes.len() > MAX_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message is too big"));
+    }
+    let mut header = [0; HEADER];
+    header[..4].clone_from_slice(&(bytes.len() as u32).to_be_bytes());
+    header[4..].clone_from_slice(&VERSION.to_be_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+// returns `None` if the peer closed the connection between messages
+pub fn read<R, T>(mut reader: R) -> Result<Option<T>, ReadError>
+where
+    R: Read,
+    T: DeserializeOwned,
+{
+    let mut header = [0; HEADER];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    }
+    let version = u16::from_be_bytes([header[4], header[5]]);
+    if version != VERSION {
+        return Err(ReadError::Version(version));
+    }
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if length > MAX_MESSAGE {
+        return Err(ReadError::TooBig(length));
+    }
+    let mut bytes = vec![0; length];
+    reader.read_exact(&mut bytes)?;
+    bincode::deserialize(&bytes)
+        .map(Some)
+        .map_err(ReadError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use vru_session::Command;
+    use super::{Request, Body, Response, Reply, NodeReply, ErrorReply, ReadError, VERSION, read, write};
+
+    #[test]
+    fn several_messages() {
+        let mut buffer = Vec::new();
+        let command = Command::Connect {
+            peer_pi: "AXhEq/scfbxy4+V2bgvrxPi5aewzKmENWvWXYdkKBic7WwO1".parse().unwrap(),
+            address: ([127, 0, 0, 1], 8224).into(),
+        };
+        write(&mut buffer, &Request::new(1, Body::Command(command))).unwrap();
+        write(&mut buffer, &Response::new(2, Ok(Reply::Node(NodeReply::Done)))).unwrap();
+
+        let mut cursor = Cursor::new(buffer);
+        let request = read::<_, Request>(&mut cursor).unwrap().unwrap();
+        assert_eq!(request.id, 1);
+        let response = read::<_, Response>(&mut cursor).unwrap().unwrap();
+        assert!(matches!(response.result, Ok(Reply::Node(NodeReply::Done))));
+        assert!(read::<_, Response>(&mut cursor).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut buffer = Vec::new();
+        let error = ErrorReply::Malformed("test".to_string());
+        write(&mut buffer, &Response::new(1, Err(error))).unwrap();
+        buffer.pop();
+        assert!(read::<_, Response>(Cursor::new(buffer)).is_err());
+    }
+
+    #[test]
+    fn other_version() {
+        let mut buffer = Vec::new();
+        write(&mut buffer, &Request::new(1, Body::Subscribe)).unwrap();
+        buffer[4..6].clone_from_slice(&(VERSION + 1).to_be_bytes());
+        // the body is not decoded
+        buffer.truncate(6);
+        match read::<_, Request>(Cursor::new(buffer)) {
+            Err(ReadError::Version(version)) => assert_eq!(version, VERSION + 1),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
@@ -1,33 +1,426 @@
-use std::path::Path;
-use sled::Db;
+use std::{convert::TryFrom, net::SocketAddr, path::Path, time::SystemTime};
+use sled::{Db, Tree};
+use thiserror::Error;
 use rac::{Array, generic_array::typenum};
-use vru_session::handshake::{SecretKey, PublicKey};
+use vru_session::handshake::{SecretKey, PublicKey, Identity};
+use super::{
+    control::{ErrorReply, KnownPeer},
+    seal::{self, Kdf, SealError},
+};
 
+// the node remembers only a few recent addresses of the peer
+const MAX_ADDRESSES: usize = 4;
+
+// the default tree holds only the version of the schema, big endian
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+const SCHEMA_VERSION: u32 = 1;
+
+// the seed is stored either in plaintext under `seed`,
+// or encrypted with the passphrase under `sealed_seed`
+const KEYS: &[u8] = b"keys";
+const SEED: &[u8] = b"seed";
+const SEALED_SEED: &[u8] = b"sealed_seed";
+
+// known peers by identity
+const PEERS: &[u8] = b"peers";
+
+// reserved for the message history
+const MESSAGES: &[u8] = b"messages";
+
+type Migration = fn(&Db) -> Result<(), DatabaseError>;
+
+// the migration `i` upgrades the schema from the version `i` to `i + 1`,
+// it should be safe to run it again if the node stops before the version is stored
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [named_trees];
+
+#[derive(Debug, Error)]
+pub enum DatabaseError {
+    #[error("{}", _0)]
+    Sled(#[from] sled::Error),
+    #[error("bad record: {}", _0)]
+    Record(#[from] bincode::Error),
+    #[error("the seed is encrypted, passphrase is required")]
+    Locked,
+    #[error("cannot decrypt the seed: {}", _0)]
+    Seal(#[from] SealError),
+    #[error("bad seed length: {}", _0)]
+    SeedLength(usize),
+    #[error("there is no seed")]
+    NoSeed,
+    #[error("the seed already exists")]
+    SeedExists,
+    #[error("database schema version {} is newer than supported {}", _0, SCHEMA_VERSION)]
+    SchemaVersion(u32),
+    #[error("bad schema version length: {}", _0)]
+    SchemaVersionLength(usize),
+}
+
+#[derive(Clone)]
 pub struct Database {
     db: Db,
+    keys: Tree,
+    peers: Tree,
+    kdf: Kdf,
 }
 
 impl Database {
-    pub fn open<P>(path: P) -> sled::Result<Self>
+    pub fn open<P>(path: P) -> Result<Self, DatabaseError>
     where
         P: AsRef<Path>,
     {
+        Self::with_db(sled::open(path)?)
+    }
+
+    fn with_db(db: Db) -> Result<Self, DatabaseError> {
+        migrate(&db)?;
         Ok(Database {
-            db: sled::open(path)?,
+            keys: db.open_tree(KEYS)?,
+            peers: db.open_tree(PEERS)?,
+            db,
+            kdf: Kdf::default(),
         })
     }
 
-    pub fn key_or_insert<F>(&self, randomize: F) -> sled::Result<(PublicKey, SecretKey)>
+    // if the passphrase is given, the plaintext seed is encrypted with it
+    pub fn key_or_insert<F>(
+        &self,
+        passphrase: Option<&[u8]>,
+        randomize: F,
+    ) -> Result<(PublicKey, SecretKey), DatabaseError>
     where
         F: FnOnce(&mut Array<typenum::U96>),
     {
-        let mut s = Array::default();
-        if let Some(kp) = self.db.get(b"key_seed")? {
-            s.clone_from_slice(kp.as_ref());
-        } else {
-            randomize(&mut s);
-            self.db.insert(b"key_seed", s.as_ref())?;
+        let s = match self.seed(passphrase)? {
+            Some(s) => s,
+            None => {
+                let mut s = Array::default();
+                randomize(&mut s);
+                s
+            },
+        };
+        if let Some(passphrase) = passphrase {
+            if self.keys.get(SEALED_SEED)?.is_none() {
+                self.store_sealed(passphrase, &s)?;
+            }
+        } else if self.keys.get(SEED)?.is_none() {
+            self.keys.insert(SEED, s.as_ref())?;
         }
         Ok(PublicKey::gen(&s))
     }
+
+    // the old passphrase is not needed if the seed is stored in plaintext
+    pub fn change_passphrase(&self, old: Option<&[u8]>, new: &[u8]) -> Result<(), DatabaseError> {
+        let s = self.seed(old)?.ok_or(DatabaseError::NoSeed)?;
+        self.store_sealed(new, &s)
+    }
+
+    // stores the seed restored from a backup, the existing seed is kept unless `replace`
+    pub fn import_seed(
+        &self,
+        s: &Array<typenum::U96>,
+        passphrase: Option<&[u8]>,
+        replace: bool,
+    ) -> Result<(), DatabaseError> {
+        let exists = self.keys.contains_key(SEED)? || self.keys.contains_key(SEALED_SEED)?;
+        if exists && !replace {
+            return Err(DatabaseError::SeedExists);
+        }
+        match passphrase {
+            Some(passphrase) => self.store_sealed(passphrase, s),
+            None => {
+                let mut batch = sled::Batch::default();
+                batch.insert(SEED, s.as_ref());
+                batch.remove(SEALED_SEED);
+                self.keys.apply_batch(batch)?;
+                self.db.flush()?;
+                Ok(())
+            },
+        }
+    }
+
+    pub fn seed(&self, passphrase: Option<&[u8]>) -> Result<Option<Array<typenum::U96>>, DatabaseError> {
+        let bytes = match (self.keys.get(SEALED_SEED)?, passphrase) {
+            (Some(_), None) => return Err(DatabaseError::Locked),
+            (Some(sealed), Some(passphrase)) => seal::open(passphrase, sealed.as_ref())?,
+            (None, _) => match self.keys.get(SEED)? {
+                Some(plain) => plain.to_vec(),
+                None => return Ok(None),
+            },
+        };
+        if bytes.len() != 96 {
+            return Err(DatabaseError::SeedLength(bytes.len()));
+        }
+        Ok(Some(Array::clone_from_slice(&bytes)))
+    }
+
+    // replaces the plaintext seed, if any, atomically,
+    // sled does not overwrite the removed value, it stays in the files until they are reclaimed
+    fn store_sealed(&self, passphrase: &[u8], s: &Array<typenum::U96>) -> Result<(), DatabaseError> {
+        if self.keys.contains_key(SEED)? {
+            log::warn!(
+                "the plaintext seed is encrypted, but it might remain in the database files, \
+                 to get rid of it, export the seed and import it into a new node directory",
+            );
+        }
+        let sealed = seal::seal(self.kdf, passphrase, s.as_ref())?;
+        let mut batch = sled::Batch::default();
+        batch.insert(SEALED_SEED, sealed);
+        batch.remove(SEED);
+        self.keys.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn peers(&self) -> Result<Vec<KnownPeer>, DatabaseError> {
+        self.peers
+            .iter()
+            .values()
+            .map(|value| Ok(bincode::deserialize(value?.as_ref())?))
+            .collect()
+    }
+
+    pub fn add_peer(
+        &self,
+        identity: &Identity,
+        address: Option<SocketAddr>,
+        trusted: bool,
+    ) -> Result<KnownPeer, DatabaseError> {
+        self.update_peer(identity, |peer| {
+            peer.trusted = trusted;
+            if let Some(address) = address {
+                add_address(peer, address);
+            }
+        })
+    }
+
+    pub fn remove_peer(&self, identity: &Identity) -> Result<Option<KnownPeer>, DatabaseError> {
+        match self.peers.remove(identity)? {
+            Some(value) => Ok(Some(bincode::deserialize(value.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    // the address the peer is reached at by an outgoing session
+    pub fn peer_address(
+        &self,
+        identity: &Identity,
+        address: SocketAddr,
+    ) -> Result<KnownPeer, DatabaseError> {
+        self.update_peer(identity, |peer| add_address(peer, address))
+    }
+
+    // the handshake with the peer is done, an unknown peer is not remembered,
+    // otherwise anyone might fill the database with new identities
+    pub fn peer_seen(&self, public_key: &PublicKey) -> Result<Option<KnownPeer>, DatabaseError> {
+        let now = SystemTime::now();
+        self.modify_peer(&public_key.identity(), false, |peer| {
+            peer.public_key = Some(public_key.clone());
+            peer.last_seen = Some(now);
+        })
+    }
+
+    fn update_peer<F>(&self, identity: &Identity, f: F) -> Result<KnownPeer, DatabaseError>
+    where
+        F: FnMut(&mut KnownPeer),
+    {
+        self.modify_peer(identity, true, f).map(|peer| peer.expect("the peer is created"))
+    }
+
+    // the control socket and the event thread update the same records concurrently
+    fn modify_peer<F>(
+        &self,
+        identity: &Identity,
+        create: bool,
+        mut f: F,
+    ) -> Result<Option<KnownPeer>, DatabaseError>
+    where
+        F: FnMut(&mut KnownPeer),
+    {
+        loop {
+            let old = self.peers.get(identity)?;
+            let mut peer = match &old {
+                Some(value) => bincode::deserialize(value.as_ref())?,
+                None if !create => return Ok(None),
+                None => KnownPeer {
+                    identity: identity.clone(),
+                    public_key: None,
+                    addresses: Vec::new(),
+                    first_seen: SystemTime::now(),
+                    last_seen: None,
+                    trusted: false,
+                },
+            };
+            f(&mut peer);
+            let new = bincode::serialize(&peer)?;
+            if self.peers.compare_and_swap(identity, old, Some(new))?.is_ok() {
+                return Ok(Some(peer));
+            }
+        }
+    }
+}
+
+fn migrate(db: &Db) -> Result<(), DatabaseError> {
+    // the database without the version is either new or created by the first release
+    let version = match db.get(SCHEMA_VERSION_KEY)? {
+        Some(value) => {
+            let bytes = <[u8; 4]>::try_from(value.as_ref())
+                .map_err(|_| DatabaseError::SchemaVersionLength(value.len()))?;
+            u32::from_be_bytes(bytes)
+        },
+        None => 0,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(DatabaseError::SchemaVersion(version));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log::info!("migrate database schema from version {}", from);
+        migration(db)?;
+        db.insert(SCHEMA_VERSION_KEY, &(from as u32 + 1).to_be_bytes())?;
+        db.flush()?;
+    }
+    Ok(())
+}
+
+// the first release kept the seed in the default tree
+fn named_trees(db: &Db) -> Result<(), DatabaseError> {
+    use sled::{Transactional, transaction::TransactionError};
+
+    let keys = db.open_tree(KEYS)?;
+    db.open_tree(PEERS)?;
+    db.open_tree(MESSAGES)?;
+    let default: &Tree = db;
+    (default, &keys)
+        .transaction(|(default, keys)| {
+            for (old, new) in [(&b"key_seed"[..], SEED), (&b"key_seed_sealed"[..], SEALED_SEED)] {
+                if let Some(value) = default.remove(old)? {
+                    keys.insert(new, value)?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(|error: TransactionError<()>| match error {
+            TransactionError::Storage(error) => DatabaseError::Sled(error),
+            TransactionError::Abort(()) => unreachable!(),
+        })
+}
+
+fn add_address(peer: &mut KnownPeer, address: SocketAddr) {
+    peer.addresses.retain(|a| *a != address);
+    peer.addresses.insert(0, address);
+    peer.addresses.truncate(MAX_ADDRESSES);
+}
+
+impl From<DatabaseError> for ErrorReply {
+    fn from(error: DatabaseError) -> Self {
+        ErrorReply::Database(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rac::{Array, generic_array::typenum};
+    use vru_session::handshake::PublicKey;
+    use crate::seal::Kdf;
+    use super::{
+        Database, DatabaseError, MAX_ADDRESSES, SCHEMA_VERSION, SCHEMA_VERSION_KEY, SEED,
+        SEALED_SEED,
+    };
+
+    fn temporary() -> Database {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Database {
+            kdf: Kdf {
+                m_cost: 64,
+                t_cost: 1,
+                p_cost: 1,
+            },
+            ..Database::with_db(db).unwrap()
+        }
+    }
+
+    #[test]
+    fn migrate_first_release() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert(b"key_seed", &[1; 96][..]).unwrap();
+        let (pk, _) = PublicKey::gen(&Array::clone_from_slice(&[1; 96]));
+        let peers = db.open_tree(b"peers").unwrap();
+        let peer = super::KnownPeer {
+            identity: pk.identity(),
+            public_key: None,
+            addresses: Vec::new(),
+            first_seen: std::time::SystemTime::now(),
+            last_seen: None,
+            trusted: true,
+        };
+        peers.insert(pk.identity(), bincode::serialize(&peer).unwrap()).unwrap();
+
+        let db = Database::with_db(db).unwrap();
+        assert!(db.db.get(b"key_seed").unwrap().is_none());
+        let (migrated, _) = db.key_or_insert(None, |_| unreachable!()).unwrap();
+        assert_eq!(migrated.identity(), pk.identity());
+        assert!(db.peers().unwrap()[0].trusted);
+
+        let version = db.db.get(SCHEMA_VERSION_KEY).unwrap().unwrap();
+        assert_eq!(version.as_ref(), SCHEMA_VERSION.to_be_bytes());
+        let names = db.db.tree_names();
+        assert!(names.iter().any(|name| name.as_ref() == b"messages"));
+    }
+
+    #[test]
+    fn newer_schema() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.insert(SCHEMA_VERSION_KEY, &(SCHEMA_VERSION + 1).to_be_bytes()).unwrap();
+        assert!(matches!(Database::with_db(db), Err(DatabaseError::SchemaVersion(_))));
+    }
+
+    #[test]
+    fn passphrase() {
+        let db = temporary();
+        let (pk, _) = db.key_or_insert(None, |s| s[0] = 1).unwrap();
+        let identity = pk.identity();
+
+        // migrate on the first unlock
+        let (pk, _) = db.key_or_insert(Some(b"old"), |_| unreachable!()).unwrap();
+        assert_eq!(pk.identity(), identity);
+        assert!(db.keys.get(SEED).unwrap().is_none());
+        assert!(db.keys.get(SEALED_SEED).unwrap().is_some());
+        assert!(matches!(db.key_or_insert(None, |_| ()), Err(DatabaseError::Locked)));
+        assert!(matches!(db.key_or_insert(Some(b"new"), |_| ()), Err(DatabaseError::Seal(_))));
+
+        db.change_passphrase(Some(b"old"), b"new").unwrap();
+        let (pk, _) = db.key_or_insert(Some(b"new"), |_| unreachable!()).unwrap();
+        assert_eq!(pk.identity(), identity);
+
+        let s = db.seed(Some(b"new")).unwrap().unwrap();
+        assert!(matches!(db.import_seed(&s, None, false), Err(DatabaseError::SeedExists)));
+        db.import_seed(&s, None, true).unwrap();
+        let (pk, _) = db.key_or_insert(None, |_| unreachable!()).unwrap();
+        assert_eq!(pk.identity(), identity);
+    }
+
+    #[test]
+    fn address_book() {
+        let db = temporary();
+        let (pk, _) = PublicKey::gen(&Array::<typenum::U96>::default());
+        let identity = pk.identity();
+
+        for port in 0..6 {
+            db.peer_address(&identity, ([127, 0, 0, 1], 8000 + port).into()).unwrap();
+        }
+        let peer = db.add_peer(&identity, Some(([127, 0, 0, 1], 8001).into()), true).unwrap();
+        assert!(peer.trusted && peer.public_key.is_none());
+        assert_eq!(peer.addresses.len(), MAX_ADDRESSES);
+        assert_eq!(peer.addresses[0].port(), 8001);
+        assert_eq!(peer.addresses[1].port(), 8005);
+
+        let peer = db.peer_seen(&pk).unwrap().unwrap();
+        assert!(peer.trusted && peer.public_key.is_some() && peer.last_seen.is_some());
+        let (stranger, _) = PublicKey::gen(&Array::clone_from_slice(&[1; 96]));
+        assert!(db.peer_seen(&stranger).unwrap().is_none());
+        assert_eq!(db.peers().unwrap().len(), 1);
+
+        assert!(db.remove_peer(&identity).unwrap().is_some());
+        assert!(db.remove_peer(&identity).unwrap().is_none());
+        assert!(db.peers().unwrap().is_empty());
+    }
 }
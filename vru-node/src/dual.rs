@@ -0,0 +1,325 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, atomic::AtomicBool, mpsc},
+    thread,
+};
+use thiserror::Error;
+use crate::control::ErrorReply;
+use vru_session::{
+    self as session,
+    Command,
+    Reply,
+    Status,
+    Event,
+    NodeDisconnected,
+    ProcessorFactory,
+    handshake::{PublicKey, SecretKey, Identity},
+};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Error)]
+pub enum DualError {
+    #[error("tcp: {}", _0)]
+    Tcp(vru_tcp::NodeError),
+    #[error("udp: {}", _0)]
+    Udp(vru_udp::NodeError),
+    #[error("already connected, identity: {}, transport: {:?}", _0, _1)]
+    AlreadyConnected(Identity, Transport),
+    #[error("no session, identity: {}", _0)]
+    NoSession(Identity),
+}
+
+pub struct DualRef(mpsc::Receiver<Event<DualError, SocketAddr>>);
+
+impl session::NodeRef<DualError, SocketAddr> for DualRef {
+    fn recv(&self) -> Result<Event<DualError, SocketAddr>, NodeDisconnected> {
+        self.0.recv().map_err(|mpsc::RecvError| NodeDisconnected)
+    }
+
+    fn try_recv(&self) -> Result<Option<Event<DualError, SocketAddr>>, NodeDisconnected> {
+        match self.0.try_recv() {
+            Ok(event) => Ok(Some(event)),
+            Err(mpsc::TryRecvError::Empty) => Ok(None),
+            Err(mpsc::TryRecvError::Disconnected) => Err(NodeDisconnected),
+        }
+    }
+}
+
+// listens on both transports with the same identity,
+// the first established session with the peer is used, the later one is closed
+pub struct DualNode<P>
+where
+    P: ProcessorFactory,
+{
+    tcp: vru_tcp::Node<P>,
+    udp: vru_udp::Node<P>,
+    sessions: Arc<Mutex<HashMap<Identity, Transport>>>,
+    forwarders: [thread::JoinHandle<()>; 2],
+}
+
+impl<P> DualNode<P>
+where
+    P: ProcessorFactory + Clone + Send + 'static,
+    P::Processor: Send + 'static,
+{
+    // the command goes to the transport of the session with the peer
+    fn route(
+        &self,
+        peer: Identity,
+        command: Command<SocketAddr>,
+    ) -> Result<Reply<SocketAddr>, DualError> {
+        use vru_session::Node as _;
+
+        let transport = self.sessions.lock().unwrap().get(&peer).cloned();
+        match transport {
+            Some(Transport::Tcp) => self.tcp.command(command).map_err(DualError::Tcp),
+            Some(Transport::Udp) => self.udp.command(command).map_err(DualError::Udp),
+            None => Err(DualError::NoSession(peer)),
+        }
+    }
+
+    // the transports may listen on different addresses,
+    // given the same address with the port 0 the udp socket takes the port of the tcp listener
+    pub fn spawn_on(
+        sk: SecretKey,
+        pk: PublicKey,
+        tcp_address: SocketAddr,
+        udp_address: SocketAddr,
+        processor_factory: P,
+        running: Arc<AtomicBool>,
+    ) -> Result<(Self, DualRef), DualError> {
+        use vru_session::Node as _;
+
+        let (tcp, tcp_ref) = vru_tcp::Node::spawn(
+            sk.clone(),
+            pk.clone(),
+            tcp_address,
+            processor_factory.clone(),
+            running.clone(),
+        )
+        .map_err(DualError::Tcp)?;
+        let udp_address = if udp_address == tcp_address && udp_address.port() == 0 {
+            match tcp.command(Command::Status).map_err(DualError::Tcp)? {
+                Reply::Status(status) => status.address,
+                _ => udp_address,
+            }
+        } else {
+            udp_address
+        };
+        let (udp, udp_ref) =
+            vru_udp::Node::spawn(sk, pk, udp_address, processor_factory, running)
+                .map_err(DualError::Udp)?;
+
+        let (sender, rx) = mpsc::channel();
+        let sessions = Arc::new(Mutex::new(HashMap::new()));
+        let forwarders = [
+            forward(
+                tcp_ref,
+                Transport::Tcp,
+                DualError::Tcp,
+                {
+                    let sessions = tcp.sessions();
+                    move |peer| sessions.disconnect(peer)
+                },
+                &sessions,
+                &sender,
+            ),
+            forward(
+                udp_ref,
+                Transport::Udp,
+                DualError::Udp,
+                {
+                    let sessions = udp.sessions();
+                    move |peer| sessions.disconnect(peer)
+                },
+                &sessions,
+                &sender,
+            ),
+        ];
+
+        Ok((
+            DualNode {
+                tcp,
+                udp,
+                sessions,
+                forwarders,
+            },
+            DualRef(rx),
+        ))
+    }
+}
+
+impl<P> session::Node<P> for DualNode<P>
+where
+    P: ProcessorFactory + Clone + Send + 'static,
+    P::Processor: Send + 'static,
+{
+    type Error = DualError;
+    type Ref = DualRef;
+    type Address = SocketAddr;
+
+    fn spawn(
+        sk: SecretKey,
+        pk: PublicKey,
+        address: Self::Address,
+        processor_factory: P,
+        running: Arc<AtomicBool>,
+    ) -> Result<(Self, Self::Ref), Self::Error> {
+        Self::spawn_on(sk, pk, address, address, processor_factory, running)
+    }
+
+    fn command(&self, command: Command<Self::Address>) -> Result<Reply<Self::Address>, Self::Error> {
+        match command {
+            Command::Connect { peer_pi, address } => {
+                let transport = self.sessions.lock().unwrap().get(&peer_pi).cloned();
+                match transport {
+                    Some(transport) => Err(DualError::AlreadyConnected(peer_pi, transport)),
+                    // prefer the stream transport for outgoing connections
+                    None => self
+                        .tcp
+                        .command(Command::Connect { peer_pi, address })
+                        .map_err(DualError::Tcp),
+                }
+            },
+            Command::Local {
+                destination,
+                command,
+            } => self.route(destination.clone(), Command::Local {
+                destination,
+                command,
+            }),
+            Command::Disconnect { peer_pi } => {
+                self.route(peer_pi.clone(), Command::Disconnect { peer_pi })
+            },
+            Command::AuthString { peer_pi } => {
+                self.route(peer_pi.clone(), Command::AuthString { peer_pi })
+            },
+            Command::Identity => self.tcp.command(Command::Identity).map_err(DualError::Tcp),
+            Command::ListPeers => {
+                let mut peers = Vec::new();
+                for reply in [
+                    self.tcp.command(Command::ListPeers).map_err(DualError::Tcp)?,
+                    self.udp.command(Command::ListPeers).map_err(DualError::Udp)?,
+                ] {
+                    if let Reply::Peers(p) = reply {
+                        peers.extend(p);
+                    }
+                }
+                // the duplicate session is listed until it is closed
+                Ok(Reply::Peers(peers))
+            },
+            Command::Status => match self.tcp.command(Command::Status).map_err(DualError::Tcp)? {
+                Reply::Status(status) => Ok(Reply::Status(Status {
+                    transport: "tcp, udp".to_string(),
+                    peers: self.sessions.lock().unwrap().len(),
+                    ..status
+                })),
+                reply => Ok(reply),
+            },
+        }
+    }
+
+    // each transport counts its own sessions
+    fn limit_incoming(&self, max: usize) {
+        self.tcp.limit_incoming(max);
+        self.udp.limit_incoming(max);
+    }
+
+    fn join(self) {
+        let DualNode {
+            tcp,
+            udp,
+            forwarders,
+            ..
+        } = self;
+        tcp.join();
+        udp.join();
+        for forwarder in forwarders {
+            forwarder.join().unwrap();
+        }
+    }
+}
+
+fn forward<R, E, F, D>(
+    node_ref: R,
+    transport: Transport,
+    wrap: F,
+    disconnect: D,
+    sessions: &Arc<Mutex<HashMap<Identity, Transport>>>,
+    sender: &mpsc::Sender<Event<DualError, SocketAddr>>,
+) -> thread::JoinHandle<()>
+where
+    R: session::NodeRef<E, SocketAddr> + Send + 'static,
+    F: Fn(E) -> DualError + Send + 'static,
+    D: Fn(&Identity) -> bool + Send + 'static,
+{
+    let sessions = sessions.clone();
+    let sender = sender.clone();
+    thread::Builder::new()
+        .name(format!("node-forward-{:?}", transport).to_lowercase())
+        .spawn(move || {
+            while let Ok(event) = node_ref.recv() {
+                let event = match event {
+                    Event::Error(error) => Event::Error(wrap(error)),
+                    Event::DebugInfo(info) => Event::DebugInfo(info),
+                    Event::Local { source, local } => {
+                        // the duplicate might deliver messages before it is closed
+                        let sessions = sessions.lock().unwrap();
+                        if sessions.get(&source.identity()) != Some(&transport) {
+                            continue;
+                        }
+                        drop(sessions);
+                        Event::Local { source, local }
+                    },
+                    Event::HandshakeDone { peer, address, incoming } => {
+                        let mut sessions = sessions.lock().unwrap();
+                        let existing = *sessions.entry(peer.identity()).or_insert(transport);
+                        drop(sessions);
+                        if existing != transport {
+                            log::info!(
+                                "close the duplicate session with {} over {:?}, keep {:?}",
+                                peer.identity(),
+                                transport,
+                                existing,
+                            );
+                            if !disconnect(&peer.identity()) {
+                                log::debug!("the duplicate session with {} is gone already", peer.identity());
+                            }
+                            continue;
+                        }
+                        Event::HandshakeDone { peer, address, incoming }
+                    },
+                    Event::Disconnected { peer } => {
+                        let mut sessions = sessions.lock().unwrap();
+                        if sessions.get(&peer) != Some(&transport) {
+                            continue;
+                        }
+                        sessions.remove(&peer);
+                        drop(sessions);
+                        Event::Disconnected { peer }
+                    },
+                };
+                if sender.send(event).is_err() {
+                    break;
+                }
+            }
+        })
+        .expect("failed to spawn forward thread")
+}
+
+impl From<DualError> for ErrorReply {
+    fn from(error: DualError) -> Self {
+        match error {
+            DualError::Tcp(error) => error.into(),
+            DualError::Udp(error) => error.into(),
+            DualError::AlreadyConnected(identity, _) => ErrorReply::AlreadyConnected(identity),
+            DualError::NoSession(identity) => ErrorReply::NoSession(identity),
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod control;
+pub mod database;
+pub mod dual;
+pub mod mnemonic;
+pub mod seal;
@@ -1,94 +1,233 @@
 use std::{
-    os::unix::net::UnixListener,
+    collections::HashMap,
+    os::unix::net::{UnixListener, UnixStream},
     path::Path,
-    io, fs,
+    io, fs, thread,
+    net::Shutdown,
     sync::{
-        Arc,
+        Arc, Mutex,
         atomic::{Ordering, AtomicBool},
+        mpsc,
     },
-    marker::PhantomData,
     time::Duration,
 };
-use serde::de::DeserializeOwned;
 use popol::{Sources, Events, interest};
+use vru_node::control::{self, Request, Body, Response, Reply, ErrorReply, Notification, ReadError};
+
+#[derive(Clone, Eq, PartialEq)]
+enum Source {
+    Listener,
+    Client(u64),
+}
+
+// the events a subscriber might lag behind, a slower subscriber is dropped
+const SUBSCRIBER_QUEUE: usize = 256;
+
+// connections which receive events, each one is written by its own thread
+#[derive(Clone, Default)]
+pub struct Subscribers(Arc<Mutex<Vec<Subscriber>>>);
+
+struct Subscriber {
+    sender: mpsc::SyncSender<Notification>,
+    // shut down to stop the writer which is blocked on the stream
+    stream: UnixStream,
+}
+
+impl Subscribers {
+    // the id of the subscribe request is the id of every event
+    fn add(&self, id: u64, stream: UnixStream) {
+        let writer = match stream.try_clone() {
+            Ok(v) => v,
+            Err(error) => {
+                log::warn!("failed to add subscriber, error: {}", error);
+                return;
+            },
+        };
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_QUEUE);
+        let spawned = thread::Builder::new()
+            .name("node-subscriber".to_string())
+            .spawn(move || {
+                for notification in receiver {
+                    let response = Response::new(id, Ok(Reply::Event(notification)));
+                    if let Err(error) = control::write(&writer, &response) {
+                        log::info!("drop subscriber, error: {}", error);
+                        break;
+                    }
+                }
+            });
+        match spawned {
+            Ok(_) => self.0.lock().unwrap().push(Subscriber { sender, stream }),
+            Err(error) => log::warn!("failed to spawn subscriber thread, error: {}", error),
+        }
+    }
+
+    // never blocks, drops the subscriber if its writer is gone or its queue is full
+    pub fn publish(&self, notification: Notification) {
+        let mut subscribers = self.0.lock().unwrap();
+        subscribers.retain(|subscriber| {
+            match subscriber.sender.try_send(notification.clone()) {
+                Ok(()) => true,
+                Err(mpsc::TrySendError::Full(_)) => {
+                    log::info!("drop subscriber, it does not read events");
+                    let _ = subscriber.stream.shutdown(Shutdown::Both);
+                    false
+                },
+                Err(mpsc::TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
 
 pub struct CommandListener {
     running: Arc<AtomicBool>,
+    subscribers: Subscribers,
     listener: UnixListener,
-    sources: Sources<()>,
-    events: Events<()>,
-}
-
-pub struct CommandListenerIter<T>
-where
-    T: DeserializeOwned,
-{
-    inner: CommandListener,
-    phantom_data: PhantomData<T>,
+    clients: HashMap<u64, UnixStream>,
+    next_client: u64,
+    sources: Sources<Source>,
+    events: Events<Source>,
 }
 
 impl CommandListener {
-    pub fn bind<P>(path: P, running: Arc<AtomicBool>) -> io::Result<Self>
+    pub fn bind<P>(path: P, running: Arc<AtomicBool>, subscribers: Subscribers) -> io::Result<Self>
     where
         P: AsRef<Path>,
     {
         let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        let mut sources = Sources::with_capacity(8);
+        sources.register(Source::Listener, &listener, interest::READ);
         Ok(CommandListener {
             running,
-            listener: UnixListener::bind(path)?,
-            sources: Sources::with_capacity(1),
-            events: Events::with_capacity(1),
+            subscribers,
+            listener,
+            clients: HashMap::new(),
+            next_client: 0,
+            sources,
+            events: Events::with_capacity(8),
         })
     }
 
-    pub fn into_iter<T>(self) -> CommandListenerIter<T>
+    // serves clients until the node stops, each client may send several requests,
+    // every request gets a response with the same id,
+    // the listener handles subscriptions itself and passes other requests to `handle`
+    pub fn run<F>(mut self, mut handle: F)
     where
-        T: DeserializeOwned,
+        F: FnMut(Body) -> Result<Reply, ErrorReply>,
     {
-        CommandListenerIter {
-            inner: self,
-            phantom_data: PhantomData,
+        while self.running.load(Ordering::Acquire) {
+            match self.sources.wait_timeout(&mut self.events, Duration::from_secs(2)) {
+                Ok(()) => (),
+                Err(error) if error.kind() == io::ErrorKind::TimedOut => continue,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => return,
+                Err(error) => {
+                    log::error!("failed to wait control socket, error: {}", error);
+                    return;
+                },
+            }
+
+            let ready = self
+                .events
+                .iter()
+                .map(|(source, event)| (source.clone(), event.readable || event.hangup))
+                .collect::<Vec<_>>();
+            for (source, readable) in ready {
+                match source {
+                    Source::Listener => self.accept(),
+                    Source::Client(id) if readable => match self.serve(id, &mut handle) {
+                        Served::Keep => (),
+                        Served::Close => {
+                            self.sources.unregister(&Source::Client(id));
+                            self.clients.remove(&id);
+                        },
+                        Served::Subscribe(request_id) => {
+                            self.sources.unregister(&Source::Client(id));
+                            if let Some(stream) = self.clients.remove(&id) {
+                                self.subscribers.add(request_id, stream);
+                            }
+                        },
+                    },
+                    Source::Client(_) => (),
+                }
+            }
         }
     }
-}
-
-impl<T> Iterator for CommandListenerIter<T>
-where
-    T: DeserializeOwned,
-{
-    type Item = bincode::Result<T>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.inner
-            .sources
-            .register((), &self.inner.listener, interest::READ);
+    fn accept(&mut self) {
         loop {
-            match self
-                .inner
-                .sources
-                .wait_timeout(&mut self.inner.events, Duration::from_secs(2))
-            {
-                Ok(()) => break,
-                Err(error) if error.kind() == io::ErrorKind::TimedOut => {
-                    if !self.inner.running.load(Ordering::Acquire) {
-                        return None;
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    // the client writes the whole request at once,
+                    // and a client which does not read should not block the listener
+                    let timeout = Some(Duration::from_secs(2));
+                    let configured = stream
+                        .set_read_timeout(timeout)
+                        .and_then(|()| stream.set_write_timeout(timeout));
+                    if let Err(error) = configured {
+                        log::warn!("failed to configure control connection, error: {}", error);
+                        continue;
                     }
+                    let id = self.next_client;
+                    self.next_client += 1;
+                    self.sources.register(Source::Client(id), &stream, interest::READ);
+                    self.clients.insert(id, stream);
                 },
-                Err(error) if error.kind() == io::ErrorKind::Interrupted => return None,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
                 Err(error) => {
-                    return Some(Err(error.into()));
+                    log::warn!("failed to accept control connection, error: {}", error);
+                    break;
                 },
             }
         }
-        match self.inner.listener.accept() {
-            Ok((stream, _)) => Some(bincode::deserialize_from(stream)),
+    }
+
+    fn serve<F>(&mut self, id: u64, handle: &mut F) -> Served
+    where
+        F: FnMut(Body) -> Result<Reply, ErrorReply>,
+    {
+        let stream = match self.clients.get(&id) {
+            Some(stream) => stream,
+            None => return Served::Close,
+        };
+        let (response, served) = match control::read::<_, Request>(stream) {
+            Ok(None) => return Served::Close,
+            Ok(Some(Request {
+                id,
+                body: Body::Subscribe,
+                ..
+            })) => (Response::new(id, Ok(Reply::Subscribed)), Served::Subscribe(id)),
+            Ok(Some(Request { id, body, .. })) => (Response::new(id, handle(body)), Served::Keep),
+            // the id of the request is unknown, respond and close the stream
+            Err(ReadError::Version(requested)) => {
+                let error = ErrorReply::UnsupportedVersion {
+                    supported: control::VERSION,
+                    requested,
+                };
+                (Response::new(0, Err(error)), Served::Close)
+            },
+            Err(error @ ReadError::TooBig(_)) | Err(error @ ReadError::Malformed(_)) => {
+                // the stream is out of sync, respond and close it
+                let error = ErrorReply::Malformed(error.to_string());
+                (Response::new(0, Err(error)), Served::Close)
+            },
             Err(error) => {
-                if error.kind() == io::ErrorKind::Interrupted {
-                    None
-                } else {
-                    Some(Err(error.into()))
-                }
+                log::warn!("failed to read control request, error: {}", error);
+                return Served::Close;
+            },
+        };
+        match control::write(stream, &response) {
+            Ok(()) => served,
+            Err(error) => {
+                log::warn!("failed to write control response, error: {}", error);
+                Served::Close
             },
         }
     }
 }
+
+enum Served {
+    Keep,
+    Close,
+    Subscribe(u64),
+}
@@ -1,38 +1,105 @@
 #![cfg(unix)]
 
 mod listener_unix;
-use self::listener_unix::CommandListener;
+use self::listener_unix::{CommandListener, Subscribers};
 
-mod database;
-use self::database::Database;
+mod config;
+use self::config::{Config, ConfigError, Settings, ListenOn, TransportMode};
 
-use std::path::PathBuf;
+use std::{
+    env, fmt, fs,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    net::SocketAddr,
+    sync::{Arc, atomic::AtomicBool},
+};
 use structopt::StructOpt;
+use vru_session::{Node as _, handshake::Identity};
+use vru_node::{
+    control::{Body, AddressBook, Reply, ErrorReply, Notification},
+    database::Database,
+    dual::DualNode,
+};
 
 #[derive(StructOpt)]
 struct Args {
     #[structopt(long)]
     path: PathBuf,
-    #[structopt(long)]
-    port: u16,
+    #[structopt(long, help = "the config file, default is config.toml in the node directory")]
+    config: Option<PathBuf>,
+    #[structopt(long, help = "tcp, udp or both, default is tcp")]
+    transport: Option<TransportMode>,
+    #[structopt(long, help = "listen on 0.0.0.0 with this port")]
+    port: Option<u16>,
+    #[structopt(long, conflicts_with = "port")]
+    address: Option<SocketAddr>,
+    #[structopt(long, help = "error, warn, info, debug or trace")]
+    log_level: Option<String>,
+    #[structopt(long, help = "maximal number of incoming sessions")]
+    max_incoming: Option<usize>,
+    #[structopt(long, help = "the control socket, relative to the node directory")]
+    control_socket: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "read the passphrase from the terminal instead of VRU_PASSPHRASE, \
+                a plaintext seed is encrypted on first unlock"
+    )]
+    ask_passphrase: bool,
+}
+
+impl Args {
+    // the flags override the file
+    fn settings(&self) -> Result<Settings, ConfigError> {
+        let path = self
+            .config
+            .clone()
+            .unwrap_or_else(|| self.path.join(config::FILE_NAME));
+        let mut config = Config::load(&path)?;
+        if let Some(transport) = self.transport {
+            config.transport = Some(transport);
+        }
+        let address = match (self.address, self.port) {
+            (Some(address), _) => Some(address),
+            (None, Some(port)) => Some(([0, 0, 0, 0], port).into()),
+            (None, None) => None,
+        };
+        if let Some(address) = address {
+            config.listen.tcp = Some(address);
+            config.listen.udp = Some(address);
+        }
+        if let Some(log_level) = &self.log_level {
+            config.log_level = Some(log_level.clone());
+        }
+        if let Some(max_incoming) = self.max_incoming {
+            config.limits.max_incoming = Some(max_incoming);
+        }
+        if let Some(control_socket) = &self.control_socket {
+            config.control.socket = Some(control_socket.clone());
+        }
+        config.settings(&self.path)
+    }
 }
 
 fn main() {
-    use std::{
-        thread,
-        sync::{
-            Arc,
-            atomic::{Ordering, AtomicBool},
-        },
-    };
+    use std::{process, sync::atomic::Ordering};
     use rand::Rng;
-    use vru_session::{Node as _, NodeRef as _};
-    use vru_tcp::Node;
 
-    let Args { path, port } = Args::from_args();
+    let args = Args::from_args();
+    let settings = match args.settings() {
+        Ok(v) => v,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        },
+    };
+    let Args {
+        path,
+        ask_passphrase,
+        ..
+    } = args;
 
     tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
+        .with_max_level(settings.log_level)
         .init();
 
     tracing::info!("running, name: {:?}", path);
@@ -50,7 +117,24 @@ fn main() {
         },
     };
 
-    let (pk, sk) = match db.key_or_insert(|s| rand::thread_rng().fill(s.as_mut())) {
+    let passphrase = if ask_passphrase {
+        match rpassword::read_password_from_tty(Some("passphrase: ")) {
+            Ok(v) if v.is_empty() => {
+                tracing::error!("fatal error: the passphrase is empty");
+                return;
+            },
+            Ok(v) => Some(v),
+            Err(error) => {
+                tracing::error!("fatal error: failed to read passphrase, error: {}", error);
+                return;
+            },
+        }
+    } else {
+        // an empty variable is the same as an unset one, the seed is never sealed with it
+        env::var("VRU_PASSPHRASE").ok().filter(|p| !p.is_empty())
+    };
+    let passphrase = passphrase.as_ref().map(String::as_bytes);
+    let (pk, sk) = match db.key_or_insert(passphrase, |s| rand::thread_rng().fill(s.as_mut())) {
         Ok(v) => v,
         Err(error) => {
             tracing::error!("fatal error: failed to obtain key, error: {}", error);
@@ -69,8 +153,32 @@ fn main() {
         }
     }
 
-    let address = ([0, 0, 0, 0], port).into();
-    let (node, node_ref) = match Node::spawn(sk, pk, address, (), running.clone()) {
+    tracing::info!("listen: {:?}", settings.listen);
+    match settings.listen {
+        ListenOn::Tcp(address) => run(db, &settings, running, |running| {
+            vru_tcp::Node::<()>::spawn(sk, pk, address, (), running)
+        }),
+        ListenOn::Udp(address) => run(db, &settings, running, |running| {
+            vru_udp::Node::<()>::spawn(sk, pk, address, (), running)
+        }),
+        ListenOn::Both { tcp, udp } => run(db, &settings, running, |running| {
+            DualNode::<()>::spawn_on(sk, pk, tcp, udp, (), running)
+        }),
+    }
+}
+
+fn run<N, F>(db: Database, settings: &Settings, running: Arc<AtomicBool>, spawn: F)
+where
+    F: FnOnce(Arc<AtomicBool>) -> Result<(N, N::Ref), N::Error>,
+    N: vru_session::Node<(), Address = SocketAddr>,
+    N::Error: fmt::Display + fmt::Debug + Send + 'static,
+    ErrorReply: From<N::Error>,
+    N::Ref: Send + 'static,
+{
+    use std::thread;
+    use vru_session::{Event, NodeRef as _};
+
+    let (node, node_ref) = match spawn(running.clone()) {
         Ok(v) => v,
         Err(error) => {
             tracing::error!("fatal error: failed to create a node, error: {}", error);
@@ -78,21 +186,57 @@ fn main() {
         },
     };
 
-    let event_stream = thread::spawn(move || {
-        while let Ok(event) = node_ref.recv() {
-            tracing::info!("{:?}", event);
-        }
-    });
+    let subscribers = Subscribers::default();
+    let event_stream = {
+        let subscribers = subscribers.clone();
+        let db = db.clone();
+        thread::spawn(move || {
+            while let Ok(event) = node_ref.recv() {
+                tracing::info!("{:?}", event);
+                // the address is known to work only after the handshake, the address
+                // of an incoming session is the source port of the peer, it is not dialled,
+                // a new peer is remembered only if the node connects to it
+                if let Event::HandshakeDone { peer, address, incoming } = &event {
+                    let stored = if *incoming {
+                        db.peer_seen(peer).map(drop)
+                    } else {
+                        db.peer_address(&peer.identity(), *address)
+                            .and_then(|_| db.peer_seen(peer))
+                            .map(drop)
+                    };
+                    if let Err(error) = stored {
+                        tracing::warn!("failed to store peer {}, error: {}", peer.identity(), error);
+                    }
+                }
+                if let Some(notification) = Notification::from_event(&event) {
+                    subscribers.publish(notification);
+                }
+            }
+        })
+    };
+
+    if let Some(max) = settings.max_incoming {
+        node.limit_incoming(max);
+    }
+    reconnect(&node, &db, &settings.bootstrap);
 
-    let path = path.join("ctrl.sock");
-    match CommandListener::bind(path, running) {
+    let path = &settings.control_socket;
+    match CommandListener::bind(path, running, subscribers) {
         Ok(listener) => {
-            for command in listener.into_iter() {
-                match command {
-                    Ok(command) => node.command(command),
-                    Err(error) => log::warn!("failed to receive a command, error: {}", error),
-                }
+            let permissions = fs::Permissions::from_mode(settings.control_mode);
+            if let Err(error) = fs::set_permissions(path, permissions) {
+                tracing::warn!("failed to set permissions of the control socket, error: {}", error);
             }
+            listener.run(|body| match body {
+                Body::Command(command) => Ok(Reply::Node(node.command(command)?)),
+                Body::AddressBook(command) => address_book(&db, command),
+                Body::ChangePassphrase { old, new } => {
+                    db.change_passphrase(old.as_ref().map(String::as_bytes), new.as_bytes())?;
+                    tracing::info!("passphrase is changed");
+                    Ok(Reply::Node(vru_session::Reply::Done))
+                },
+                Body::Subscribe => unreachable!("the listener handles subscriptions"),
+            });
         },
         Err(error) => log::error!("failed to listen commands, error: {}", error),
     }
@@ -100,3 +244,46 @@ fn main() {
     node.join();
     event_stream.join().unwrap();
 }
+
+// connects to bootstrap peers, and to trusted peers using the most recent address
+fn reconnect<N>(node: &N, db: &Database, bootstrap: &[(Identity, SocketAddr)])
+where
+    N: vru_session::Node<(), Address = SocketAddr>,
+    N::Error: fmt::Display,
+{
+    use vru_session::Command;
+
+    let peers = match db.peers() {
+        Ok(v) => v,
+        Err(error) => {
+            tracing::error!("failed to read known peers, error: {}", error);
+            return;
+        },
+    };
+    let trusted = peers
+        .into_iter()
+        .filter(|peer| peer.trusted && !bootstrap.iter().any(|(pi, _)| *pi == peer.identity))
+        .filter_map(|peer| Some((peer.identity, *peer.addresses.first()?)));
+    for (peer_pi, address) in bootstrap.iter().cloned().chain(trusted) {
+        tracing::info!("reconnect to {}, address: {}", peer_pi, address);
+        let command = Command::Connect { peer_pi, address };
+        if let Err(error) = node.command(command) {
+            tracing::warn!("failed to reconnect, error: {}", error);
+        }
+    }
+}
+
+fn address_book(db: &Database, command: AddressBook) -> Result<Reply, ErrorReply> {
+    match command {
+        AddressBook::Add {
+            peer,
+            address,
+            trusted,
+        } => Ok(Reply::AddressBook(vec![db.add_peer(&peer, address, trusted)?])),
+        AddressBook::List => Ok(Reply::AddressBook(db.peers()?)),
+        AddressBook::Remove { peer } => match db.remove_peer(&peer)? {
+            Some(removed) => Ok(Reply::AddressBook(vec![removed])),
+            None => Err(ErrorReply::UnknownPeer(peer)),
+        },
+    }
+}
@@ -0,0 +1,96 @@
+use rac::{Array, generic_array::typenum};
+use bip39::Language;
+use sha3::{
+    Sha3_256,
+    digest::{Digest, FixedOutput},
+};
+use thiserror::Error;
+
+// the seed (768 bits) and the checksum (first 24 bits of sha3-256 of the seed)
+// are split in 11-bit indices in the english bip39 word list
+const SEED_SIZE: usize = 96;
+const CHECKSUM_SIZE: usize = 3;
+pub const WORDS: usize = (SEED_SIZE + CHECKSUM_SIZE) * 8 / 11;
+
+#[derive(Debug, Error)]
+pub enum MnemonicError {
+    #[error("wrong number of words: {}, expected: {}", _0, WORDS)]
+    WordCount(usize),
+    #[error("unknown word: {}", _0)]
+    UnknownWord(String),
+    #[error("checksum mismatch")]
+    Checksum,
+}
+
+fn checksum(seed: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let hash = Sha3_256::default().chain(seed).finalize_fixed();
+    let mut checksum = [0; CHECKSUM_SIZE];
+    checksum.clone_from_slice(&hash[..CHECKSUM_SIZE]);
+    checksum
+}
+
+pub fn encode(seed: &Array<typenum::U96>) -> String {
+    let mut bytes = seed.to_vec();
+    bytes.extend_from_slice(&checksum(seed));
+    let list = Language::English.word_list();
+    (0..WORDS)
+        .map(|i| {
+            let index = (0..11).fold(0, |index, j| {
+                let bit = i * 11 + j;
+                (index << 1) | ((bytes[bit / 8] >> (7 - bit % 8)) & 1) as usize
+            });
+            list[index]
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// words are separated by any whitespace
+pub fn decode(mnemonic: &str) -> Result<Array<typenum::U96>, MnemonicError> {
+    let words = mnemonic.split_whitespace().collect::<Vec<_>>();
+    if words.len() != WORDS {
+        return Err(MnemonicError::WordCount(words.len()));
+    }
+    let mut bytes = [0; SEED_SIZE + CHECKSUM_SIZE];
+    for (i, word) in words.into_iter().enumerate() {
+        let index = Language::English
+            .find_word(&word.to_lowercase())
+            .ok_or_else(|| MnemonicError::UnknownWord(word.to_string()))?;
+        for j in 0..11 {
+            let bit = i * 11 + j;
+            bytes[bit / 8] |= (((index >> (10 - j)) & 1) as u8) << (7 - bit % 8);
+        }
+    }
+    let (seed, sum) = bytes.split_at(SEED_SIZE);
+    if checksum(seed) != sum {
+        return Err(MnemonicError::Checksum);
+    }
+    Ok(Array::clone_from_slice(seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use rac::{Array, generic_array::typenum};
+    use super::{MnemonicError, WORDS, encode, decode};
+
+    #[test]
+    fn round_trip() {
+        let mut seed = Array::<typenum::U96>::default();
+        rand::Rng::fill(&mut rand::thread_rng(), &mut seed[..]);
+        let mnemonic = encode(&seed);
+        assert_eq!(mnemonic.split(' ').count(), WORDS);
+        assert_eq!(decode(&mnemonic).unwrap(), seed);
+    }
+
+    #[test]
+    fn errors() {
+        let mnemonic = encode(&Array::default());
+        let mut words = mnemonic.split(' ').collect::<Vec<_>>();
+        assert!(matches!(decode(&words[1..].join(" ")), Err(MnemonicError::WordCount(71))));
+
+        words[0] = "zoo";
+        assert!(matches!(decode(&words.join(" ")), Err(MnemonicError::Checksum)));
+        words[0] = "vru";
+        assert!(matches!(decode(&words.join(" ")), Err(MnemonicError::UnknownWord(_))));
+    }
+}
@@ -0,0 +1,158 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, Nonce,
+    aead::{Aead, NewAead, Payload},
+};
+use thiserror::Error;
+
+// the version (1 byte), kdf parameters (3 * 4 bytes, big endian), salt and nonce
+// precede the ciphertext, the header is authenticated as associated data
+const VERSION: u8 = 1;
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const HEADER_SIZE: usize = 1 + 12 + SALT_SIZE + NONCE_SIZE;
+const TAG_SIZE: usize = 16;
+
+// the header is not trusted before decryption, limit the work it might demand
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 64;
+const MAX_P_COST: u32 = 16;
+
+#[derive(Debug, Error)]
+pub enum SealError {
+    #[error("wrong passphrase or corrupted data")]
+    WrongPassphrase,
+    #[error("sealed data is too short: {}", _0)]
+    Length(usize),
+    #[error("unsupported sealed data version: {}", _0)]
+    Version(u8),
+    #[error("key derivation error: {}", _0)]
+    Kdf(argon2::Error),
+    #[error("key derivation parameters are too high: {:?}", _0)]
+    KdfLimit(Kdf),
+}
+
+// argon2id parameters, the memory cost is in kibibytes
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Kdf {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf {
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+impl Kdf {
+    fn derive(&self, passphrase: &[u8], salt: &[u8]) -> Result<Key, SealError> {
+        let params =
+            Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32)).map_err(SealError::Kdf)?;
+        let mut key = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, salt, &mut key)
+            .map_err(SealError::Kdf)?;
+        Ok(key)
+    }
+}
+
+pub fn seal(kdf: Kdf, passphrase: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, SealError> {
+    use rand::Rng;
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.push(VERSION);
+    header.extend_from_slice(&kdf.m_cost.to_be_bytes());
+    header.extend_from_slice(&kdf.t_cost.to_be_bytes());
+    header.extend_from_slice(&kdf.p_cost.to_be_bytes());
+    let mut random = [0; SALT_SIZE + NONCE_SIZE];
+    rand::thread_rng().fill(&mut random[..]);
+    header.extend_from_slice(&random);
+
+    let (salt, nonce) = random.split_at(SALT_SIZE);
+    let key = kdf.derive(passphrase, salt)?;
+    let payload = Payload {
+        msg: plaintext,
+        aad: &header,
+    };
+    let ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| SealError::WrongPassphrase)?;
+    header.extend_from_slice(&ciphertext);
+    Ok(header)
+}
+
+pub fn open(passphrase: &[u8], sealed: &[u8]) -> Result<Vec<u8>, SealError> {
+    if sealed.len() < HEADER_SIZE + TAG_SIZE {
+        return Err(SealError::Length(sealed.len()));
+    }
+    if sealed[0] != VERSION {
+        return Err(SealError::Version(sealed[0]));
+    }
+    let (header, ciphertext) = sealed.split_at(HEADER_SIZE);
+    let u32_at = |i: usize| {
+        let mut bytes = [0; 4];
+        bytes.clone_from_slice(&header[i..(i + 4)]);
+        u32::from_be_bytes(bytes)
+    };
+    let kdf = Kdf {
+        m_cost: u32_at(1),
+        t_cost: u32_at(5),
+        p_cost: u32_at(9),
+    };
+    if kdf.m_cost > MAX_M_COST || kdf.t_cost > MAX_T_COST || kdf.p_cost > MAX_P_COST {
+        return Err(SealError::KdfLimit(kdf));
+    }
+    let (salt, nonce) = header[13..].split_at(SALT_SIZE);
+    let key = kdf.derive(passphrase, salt)?;
+    let payload = Payload {
+        msg: ciphertext,
+        aad: header,
+    };
+    ChaCha20Poly1305::new(&key)
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| SealError::WrongPassphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Kdf, SealError, seal, open};
+
+    const WEAK: Kdf = Kdf {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn round_trip() {
+        let sealed = seal(WEAK, b"passphrase", b"seed").unwrap();
+        assert_eq!(open(b"passphrase", &sealed).unwrap(), b"seed");
+        assert!(matches!(open(b"wrong", &sealed), Err(SealError::WrongPassphrase)));
+    }
+
+    #[test]
+    fn tampered_header() {
+        let mut sealed = seal(WEAK, b"passphrase", b"seed").unwrap();
+        sealed[8] ^= 1;
+        assert!(open(b"passphrase", &sealed).is_err());
+        sealed[0] = 2;
+        assert!(matches!(open(b"passphrase", &sealed), Err(SealError::Version(2))));
+        assert!(matches!(open(b"passphrase", &sealed[..20]), Err(SealError::Length(20))));
+    }
+
+    #[test]
+    fn expensive_header() {
+        let mut sealed = seal(WEAK, b"passphrase", b"seed").unwrap();
+        sealed[1..5].clone_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(open(b"passphrase", &sealed), Err(SealError::KdfLimit(_))));
+        let mut sealed = seal(WEAK, b"passphrase", b"seed").unwrap();
+        sealed[5..9].clone_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(open(b"passphrase", &sealed), Err(SealError::KdfLimit(_))));
+    }
+}
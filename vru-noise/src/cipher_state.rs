@@ -105,6 +105,31 @@ where
             })
             .map_err(|_| MacMismatch)
     }
+
+    // the nonce is explicit, for unreliable transports where messages might be lost or reordered,
+    // the caller never reuses the nonce and never mixes these with `encrypt` and `decrypt`,
+    // the key is not rotated
+    pub fn encrypt_at(&self, nonce: u64, associated_data: &[u8], buffer: &mut [u8]) -> Tag<C> {
+        let mut nonce_array = GenericArray::default();
+        C::ByteOrder::write_u64(&mut nonce_array[4..], nonce);
+        self.key
+            .encrypt_in_place_detached(&nonce_array, associated_data, buffer)
+            .unwrap()
+    }
+
+    pub fn decrypt_at(
+        &self,
+        nonce: u64,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &Tag<C>,
+    ) -> Result<(), MacMismatch> {
+        let mut nonce_array = GenericArray::default();
+        C::ByteOrder::write_u64(&mut nonce_array[4..], nonce);
+        self.key
+            .decrypt_in_place_detached(&nonce_array, associated_data, buffer, tag)
+            .map_err(|_| MacMismatch)
+    }
 }
 
 impl<C, R> fmt::Debug for Unidirectional<C, R>
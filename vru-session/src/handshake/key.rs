@@ -1,6 +1,7 @@
 use std::{ops::Mul, fmt, str::FromStr};
-use curve25519_dalek::{edwards::EdwardsPoint, scalar::Scalar};
+use curve25519_dalek::{edwards::EdwardsPoint, scalar::Scalar, traits::IsIdentity};
 use serde::{Serialize, Deserialize};
+use thiserror::Error;
 use rac::{Array, Concat, Curve, LineValid, Line, generic_array::typenum};
 use self::lattice::{Sk, Pk, PkHash};
 pub use self::lattice::{Ct, SharedSecret, Encapsulated};
@@ -39,16 +40,24 @@ impl PublicKey {
         )
     }
 
-    pub fn decompress(bytes: PublicKeyBytes) -> Self {
+    // the bytes come from the peer, the elliptic point should be valid and have a large order
+    pub fn decompress(bytes: PublicKeyBytes) -> Result<Self, KeyError> {
         let Concat(elliptic_bytes, lattice_bytes) = bytes;
+        let elliptic =
+            EdwardsPoint::try_clone_array(&elliptic_bytes).map_err(|()| KeyError::InvalidPoint)?;
+        if elliptic.is_identity() {
+            return Err(KeyError::IdentityPoint);
+        }
+        if elliptic.is_small_order() {
+            return Err(KeyError::SmallOrderPoint);
+        }
         let lattice = Pk::clone_array(&lattice_bytes);
-        let elliptic = EdwardsPoint::try_clone_array(&elliptic_bytes).unwrap();
         let lattice_hash = lattice::pk_hash(&lattice);
-        PublicKey {
+        Ok(PublicKey {
             elliptic,
             lattice,
             lattice_hash,
-        }
+        })
     }
 
     pub fn elliptic(&self) -> Array<typenum::U32> {
@@ -92,6 +101,16 @@ impl PublicKey {
     }
 }
 
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum KeyError {
+    #[error("invalid point")]
+    InvalidPoint,
+    #[error("small order point")]
+    SmallOrderPoint,
+    #[error("identity point")]
+    IdentityPoint,
+}
+
 pub type PublicKeyLatticeBytes = Array<<typenum::U32 as Mul<typenum::U34>>::Output>;
 pub type PublicKeyBytes = Concat<Array<typenum::U32>, PublicKeyLatticeBytes>;
 
@@ -106,19 +125,71 @@ impl AsRef<[u8]> for Identity {
     }
 }
 
+// the text form is base64 of the version, the hash and the checksum,
+// the version also tells the algorithm of the key, see `PublicKey::identity`
+impl Identity {
+    pub const VERSION: u8 = 1;
+
+    const CHECKSUM_SIZE: usize = 3;
+
+    // the size of the identity in the text form before base64
+    pub const SIZE: usize = 1 + 32 + Self::CHECKSUM_SIZE;
+
+    fn checksum(version: u8, hash: &[u8]) -> [u8; Self::CHECKSUM_SIZE] {
+        use sha3::{
+            Sha3_256,
+            digest::{Digest, FixedOutput},
+        };
+
+        let digest = Sha3_256::default()
+            .chain(b"vru identity")
+            .chain([version])
+            .chain(hash)
+            .finalize_fixed();
+        let mut checksum = [0; Self::CHECKSUM_SIZE];
+        checksum.clone_from_slice(&digest[..Self::CHECKSUM_SIZE]);
+        checksum
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum IdentityError {
+    #[error("bad base64: {}", _0)]
+    Base64(base64::DecodeError),
+    #[error("identity version {}, expected: {}", _0, Identity::VERSION)]
+    Version(u8),
+    #[error("identity length {}, expected: {}", _0, Identity::SIZE)]
+    Length(usize),
+    #[error("identity checksum mismatch, probably a typo")]
+    Checksum,
+}
+
 impl fmt::Display for Identity {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut bytes = [0; 33];
+        let mut bytes = [0; Identity::SIZE];
+        bytes[0] = Identity::VERSION;
         bytes[1..33].clone_from_slice(&self.hash);
+        bytes[33..].clone_from_slice(&Identity::checksum(Identity::VERSION, &self.hash));
         write!(f, "{}", base64::encode(bytes))
     }
 }
 
 impl FromStr for Identity {
-    type Err = base64::DecodeError;
+    type Err = IdentityError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let bytes = base64::decode(s)?;
+        let bytes = base64::decode(s).map_err(IdentityError::Base64)?;
+        match bytes.first() {
+            None => return Err(IdentityError::Length(0)),
+            Some(&Identity::VERSION) => (),
+            Some(&version) => return Err(IdentityError::Version(version)),
+        }
+        if bytes.len() != Identity::SIZE {
+            return Err(IdentityError::Length(bytes.len()));
+        }
+        if bytes[33..] != Identity::checksum(Identity::VERSION, &bytes[1..33]) {
+            return Err(IdentityError::Checksum);
+        }
         let mut hash = Array::default();
         hash.as_mut_slice().clone_from_slice(&bytes[1..33]);
         Ok(Identity { hash })
@@ -1,10 +1,16 @@
 mod key;
-pub use self::key::{SecretKey, PublicKey, Identity};
+pub use self::key::{SecretKey, PublicKey, Identity, IdentityError, KeyError};
 
 mod noise;
 pub use self::noise::{TrivialRotor, TrivialCipher, TrivialUnidirectional};
 
+mod sas;
+pub use self::sas::ShortAuthString;
+
 pub mod xx;
 
+mod state;
+pub use self::state::{Handshake, Initiator, Responder, Payloads, Step, Next, Established, HandshakeError};
+
 #[cfg(test)]
 mod test;
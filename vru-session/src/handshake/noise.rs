@@ -21,6 +21,17 @@ pub type Encrypted<C, L> = Concat<Array<<L as LineValid>::Length>, Tag<C>>;
 
 pub type EncryptedDefault<T> = Encrypted<Noise, T>;
 
+// the length of the payload is not known at compile time
+pub struct EncryptedPayload<C>
+where
+    C: Config,
+{
+    pub data: Vec<u8>,
+    pub tag: Tag<C>,
+}
+
+pub type EncryptedPayloadDefault = EncryptedPayload<Noise>;
+
 pub trait SymmetricStateOps<C>
 where
     C: Config,
@@ -39,6 +50,13 @@ where
     where
         L: Line,
         Encrypted<C, L>: Line;
+
+    fn encrypt_payload(self, data: Vec<u8>) -> (Self::NextState, EncryptedPayload<C>);
+
+    fn decrypt_payload(
+        self,
+        encrypted: EncryptedPayload<C>,
+    ) -> Result<(Self::NextState, Vec<u8>), MacMismatch>;
 }
 
 impl<C, N> SymmetricStateOps<C> for SymmetricState<C, Key<C, N>>
@@ -71,4 +89,19 @@ where
         let state = self.decrypt(&mut data, tag)?;
         Ok((state, L::clone_array(&data)))
     }
+
+    fn encrypt_payload(self, data: Vec<u8>) -> (Self::NextState, EncryptedPayload<C>) {
+        let mut data = data;
+        let (state, tag) = self.encrypt(&mut data);
+        (state, EncryptedPayload { data, tag })
+    }
+
+    fn decrypt_payload(
+        self,
+        encrypted: EncryptedPayload<C>,
+    ) -> Result<(Self::NextState, Vec<u8>), MacMismatch> {
+        let EncryptedPayload { mut data, tag } = encrypted;
+        let state = self.decrypt(&mut data, tag)?;
+        Ok((state, data))
+    }
 }
@@ -0,0 +1,39 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+use sha3::{
+    Sha3_256,
+    digest::{Digest, FixedOutput},
+};
+use super::Identity;
+
+// the number of decimal digits, 30 bits
+const DIGITS: u32 = 9;
+
+// short authentication string, both peers get the same digits
+// if there is no man in the middle, operators compare it out of band
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ShortAuthString(u32);
+
+impl ShortAuthString {
+    // the hash is the handshake hash, the order of identities does not matter
+    pub fn new(hash: &[u8], a: &Identity, b: &Identity) -> Self {
+        let (first, second) = if a.as_ref() <= b.as_ref() { (a, b) } else { (b, a) };
+        let digest = Sha3_256::default()
+            .chain(b"vru-sas")
+            .chain(hash)
+            .chain(first)
+            .chain(second)
+            .finalize_fixed();
+        let mut bytes = [0; 8];
+        bytes.clone_from_slice(&digest[..8]);
+        let code = u64::from_be_bytes(bytes) % 10u64.pow(DIGITS);
+        ShortAuthString(code as u32)
+    }
+}
+
+impl fmt::Display for ShortAuthString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = format!("{:09}", self.0);
+        write!(f, "{} {} {}", &s[0..3], &s[3..6], &s[6..9])
+    }
+}
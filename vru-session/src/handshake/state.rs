@@ -0,0 +1,290 @@
+use std::mem;
+use rac::{
+    Array, Line,
+    generic_array::{ArrayLength, typenum},
+};
+use thiserror::Error;
+use super::{
+    key::{PublicKey, SecretKey, Identity},
+    noise::{TrivialCipher, TrivialRotor},
+    xx::{self, Message, PayloadMessage, MessageError},
+};
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("payload too long: {}, maximum: {}", _0, xx::MAX_PAYLOAD)]
+    Payload(usize),
+    #[error("{}", _0)]
+    Message(MessageError),
+    #[error("initiator {}", _0)]
+    Initiator(xx::InitiatorsError),
+    #[error("responder {}", _0)]
+    Responder(xx::RespondersError),
+}
+
+// the responder sends its payloads in the messages 1 and 3, the initiator sends both in the message 2,
+// only the last payload of each side is sent after the peer is authenticated
+#[derive(Debug, Clone)]
+pub struct Payloads {
+    outgoing: [Vec<u8>; 2],
+    max_length: usize,
+}
+
+impl Default for Payloads {
+    fn default() -> Self {
+        Payloads {
+            outgoing: [Vec::new(), Vec::new()],
+            max_length: xx::DEFAULT_MAX_PAYLOAD,
+        }
+    }
+}
+
+impl Payloads {
+    // `max_length` limits the payloads of the peer
+    pub fn new(first: Vec<u8>, second: Vec<u8>, max_length: usize) -> Result<Self, HandshakeError> {
+        for payload in [&first, &second].iter() {
+            if payload.len() > xx::MAX_PAYLOAD {
+                return Err(HandshakeError::Payload(payload.len()));
+            }
+        }
+        Ok(Payloads {
+            outgoing: [first, second],
+            max_length,
+        })
+    }
+}
+
+pub struct Established {
+    pub cipher: TrivialCipher,
+    pub hash: Array<typenum::U32>,
+    pub peer: PublicKey,
+    // the payloads of the peer in the order it sent them
+    pub payloads: [Vec<u8>; 2],
+}
+
+pub struct Step {
+    // the number and the bytes of the message to send
+    pub message: Option<(u8, Vec<u8>)>,
+    pub next: Next,
+}
+
+pub enum Next {
+    Handshake(Box<Handshake>),
+    Established(Box<Established>),
+}
+
+// the transport drives either side of the handshake with the same code,
+// it passes messages in the order given by `expect_next`
+pub enum Handshake {
+    Initiator(Initiator),
+    Responder(Responder),
+}
+
+impl Handshake {
+    pub fn is_initiator(&self) -> bool {
+        matches!(self, Handshake::Initiator(_))
+    }
+
+    // the number of the message the state takes
+    pub fn expect_next(&self) -> u8 {
+        match self {
+            Handshake::Initiator(initiator) => initiator.expect_next(),
+            Handshake::Responder(responder) => responder.expect_next(),
+        }
+    }
+
+    pub fn step(self, message: &[u8]) -> Result<Step, HandshakeError> {
+        match self {
+            Handshake::Initiator(initiator) => initiator.step(message),
+            Handshake::Responder(responder) => responder.step(message),
+        }
+    }
+}
+
+pub struct Initiator {
+    sk: SecretKey,
+    pk: PublicKey,
+    payloads: Payloads,
+    state: InitiatorState,
+}
+
+enum InitiatorState {
+    Ephemeral(Box<xx::InitiatorsEphemeral>),
+    Final(Box<xx::InitiatorsFinal>, Box<PublicKey>, Vec<u8>),
+}
+
+impl Initiator {
+    // the initiator knows the identity of the responder, returns the message 0
+    pub fn new(
+        sk: SecretKey,
+        pk: PublicKey,
+        peer_pi: &Identity,
+        payloads: Payloads,
+    ) -> (Self, Vec<u8>) {
+        let (state, message) = xx::out0(&random(), peer_pi);
+        let initiator = Initiator {
+            sk,
+            pk,
+            payloads,
+            state: InitiatorState::Ephemeral(Box::new(state)),
+        };
+        (initiator, message.to_bytes())
+    }
+
+    pub fn expect_next(&self) -> u8 {
+        match &self.state {
+            InitiatorState::Ephemeral(_) => 1,
+            InitiatorState::Final(..) => 3,
+        }
+    }
+
+    pub fn step(self, message: &[u8]) -> Result<Step, HandshakeError> {
+        let Initiator {
+            sk,
+            pk,
+            mut payloads,
+            state,
+        } = self;
+        let max_length = payloads.max_length;
+        match state {
+            InitiatorState::Ephemeral(state) => {
+                let message = xx::Message1::from_bytes(message, max_length)
+                    .map_err(HandshakeError::Message)?;
+                let [payload_q, payload_r] = &mut payloads.outgoing;
+                let (state, peer, payload_p, message) = xx::take1_out2(
+                    &Line::clone_array(&random::<typenum::U64>()),
+                    *state,
+                    &pk,
+                    &sk,
+                    message,
+                    mem::take(payload_q),
+                    mem::take(payload_r),
+                )
+                .map_err(HandshakeError::Initiator)?;
+                let message = message.to_bytes();
+                let state = InitiatorState::Final(Box::new(state), Box::new(peer), payload_p);
+                let initiator = Initiator {
+                    sk,
+                    pk,
+                    payloads,
+                    state,
+                };
+                Ok(Step {
+                    message: Some((2, message)),
+                    next: Next::Handshake(Box::new(Handshake::Initiator(initiator))),
+                })
+            },
+            InitiatorState::Final(state, peer, payload_p) => {
+                let message = xx::Message3::from_bytes(message, max_length)
+                    .map_err(HandshakeError::Message)?;
+                let (cipher, hash, payload_s) =
+                    xx::take_3::<TrivialRotor>(*state, &pk, &sk, message)
+                        .map_err(HandshakeError::Initiator)?;
+                Ok(Step {
+                    message: None,
+                    next: Next::Established(Box::new(Established {
+                        cipher,
+                        hash,
+                        peer: *peer,
+                        payloads: [payload_p, payload_s],
+                    })),
+                })
+            },
+        }
+    }
+}
+
+pub struct Responder {
+    sk: SecretKey,
+    pk: PublicKey,
+    payloads: Payloads,
+    state: Option<Box<xx::RespondersEphemeral>>,
+}
+
+impl Responder {
+    pub fn new(sk: SecretKey, pk: PublicKey, payloads: Payloads) -> Self {
+        Responder {
+            sk,
+            pk,
+            payloads,
+            state: None,
+        }
+    }
+
+    pub fn expect_next(&self) -> u8 {
+        match &self.state {
+            None => 0,
+            Some(_) => 2,
+        }
+    }
+
+    pub fn step(self, message: &[u8]) -> Result<Step, HandshakeError> {
+        let Responder {
+            sk,
+            pk,
+            mut payloads,
+            state,
+        } = self;
+        let max_length = payloads.max_length;
+        let [payload_p, payload_s] = &mut payloads.outgoing;
+        match state {
+            None => {
+                let message = xx::Message0::from_bytes(message)
+                    .map_err(HandshakeError::Message)?;
+                let (state, message) = xx::take0_out1(
+                    &Line::clone_array(&random::<typenum::U128>()),
+                    &pk.identity(),
+                    &pk,
+                    &sk,
+                    message,
+                    mem::take(payload_p),
+                )
+                .map_err(HandshakeError::Responder)?;
+                let message = message.to_bytes();
+                let state = Some(Box::new(state));
+                let responder = Responder {
+                    sk,
+                    pk,
+                    payloads,
+                    state,
+                };
+                Ok(Step {
+                    message: Some((1, message)),
+                    next: Next::Handshake(Box::new(Handshake::Responder(responder))),
+                })
+            },
+            Some(state) => {
+                let message = xx::Message2::from_bytes(message, max_length)
+                    .map_err(HandshakeError::Message)?;
+                let (cipher, hash, peer, payload_q, payload_r, message) =
+                    xx::take2_out3::<TrivialRotor>(
+                        &random(),
+                        *state,
+                        &pk,
+                        &sk,
+                        message,
+                        mem::take(payload_s),
+                    )
+                    .map_err(HandshakeError::Responder)?;
+                Ok(Step {
+                    message: Some((3, message.to_bytes())),
+                    next: Next::Established(Box::new(Established {
+                        cipher,
+                        hash,
+                        peer,
+                        payloads: [payload_q, payload_r],
+                    })),
+                })
+            },
+        }
+    }
+}
+
+fn random<N>() -> Array<N>
+where
+    N: ArrayLength<u8>,
+{
+    let mut seed = Array::<N>::default();
+    rand::Rng::fill(&mut rand::thread_rng(), &mut seed[..]);
+    seed
+}
@@ -2,7 +2,11 @@ use rac::{
     Array, Concat, Line, LineValid,
     generic_array::{typenum, sequence::GenericSequence},
 };
-use super::{PublicKey, xx, TrivialRotor};
+use super::{
+    PublicKey, ShortAuthString, xx, TrivialRotor, Handshake, Initiator, Responder, Step, Next,
+    Established, HandshakeError, Payloads, KeyError, Identity, IdentityError,
+    xx::{Message, PayloadMessage, MessageError},
+};
 
 #[test]
 fn handshake() {
@@ -16,16 +20,17 @@ fn handshake() {
 
     let r_pi = r_pk.identity();
 
-    let orig_p = Array::<typenum::U16>::generate(|_| 0x03);
+    let orig_p = vec![0x03; 16];
     let payload_p = orig_p.clone();
-    let orig_q = Array::<typenum::U16>::generate(|_| 0x13);
+    let orig_q = vec![0x13; 16];
     let payload_q = orig_q.clone();
-    let orig_r = Array::<typenum::U16>::generate(|_| 0x23);
+    let orig_r = vec![0x23; 16];
     let payload_r = orig_r.clone();
-    let orig_s = Array::<typenum::U16>::generate(|_| 0x33);
+    let orig_s = vec![0x33; 16];
     let payload_s = orig_s.clone();
 
     let (i_state, message) = xx::out0(&i_e_seed, &r_pi);
+    let message = round_trip(message, xx::Message0::from_bytes);
     let (r_state, message) = xx::take0_out1(
         &Concat(r_e_seed, r_pq_e_seed),
         &r_pi,
@@ -33,8 +38,10 @@ fn handshake() {
         &r_sk,
         message,
         payload_p,
-    );
-    let (i_state, rr_pk, payload_p, message) = xx::take1_out2::<Array<typenum::U16>, _, _>(
+    )
+    .unwrap();
+    let message = round_trip(message, decode);
+    let (i_state, rr_pk, payload_p, message) = xx::take1_out2(
         &Concat(i_pq_e_seed, i_pq_s_seed),
         i_state,
         &i_pk,
@@ -44,18 +51,13 @@ fn handshake() {
         payload_r,
     )
     .unwrap();
+    let message = round_trip(message, decode);
     let (mut r_cipher, r_hash, ri_pk, payload_q, payload_r, message) =
-        xx::take2_out3::<Array<typenum::U16>, Array<typenum::U16>, _, TrivialRotor>(
-            &r_pq_s_seed,
-            r_state,
-            &r_pk,
-            &r_sk,
-            message,
-            payload_s,
-        )
-        .unwrap();
+        xx::take2_out3::<TrivialRotor>(&r_pq_s_seed, r_state, &r_pk, &r_sk, message, payload_s)
+            .unwrap();
+    let message = round_trip(message, decode);
     let (mut i_cipher, i_hash, payload_s) =
-        xx::take_3::<Array<typenum::U16>, TrivialRotor>(i_state, &i_pk, &i_sk, message).unwrap();
+        xx::take_3::<TrivialRotor>(i_state, &i_pk, &i_sk, message).unwrap();
 
     let reference_hash = "77316870c248ff7f6cb6ad95b473e46290f6945a97888892ae83dbe23fa7abe4";
     assert_eq!(reference_hash, hex::encode(&i_hash));
@@ -77,3 +79,332 @@ fn handshake() {
         assert_eq!(orig, a);
     }
 }
+
+// the message is the same after encoding and decoding
+fn round_trip<M, F>(message: M, decode: F) -> M
+where
+    M: Message,
+    F: Fn(&[u8]) -> Result<M, MessageError>,
+{
+    let bytes = message.to_bytes();
+    assert_eq!(bytes[..2], [xx::WIRE_VERSION, M::NUMBER]);
+    let decoded = decode(&bytes).unwrap();
+    assert_eq!(decoded.to_bytes(), bytes);
+    decoded
+}
+
+fn decode<M>(bytes: &[u8]) -> Result<M, MessageError>
+where
+    M: PayloadMessage,
+{
+    M::from_bytes(bytes, xx::MAX_PAYLOAD)
+}
+
+#[test]
+fn wire_errors() {
+    let (pk, _) = PublicKey::gen(&Array::generate(|i| i as u8));
+    let (_, message) = xx::out0(&Array::generate(|i| !i as u8), &pk.identity());
+    let bytes = message.to_bytes();
+    let length = bytes.len();
+    let max = xx::DEFAULT_MAX_PAYLOAD;
+
+    assert!(xx::Message0::from_bytes(&bytes).is_ok());
+    assert_eq!(
+        xx::Message0::from_bytes(&[]).err(),
+        Some(MessageError::Truncated(0))
+    );
+    assert_eq!(
+        xx::Message0::from_bytes(&bytes[..(length - 1)]).err(),
+        Some(MessageError::Truncated(0)),
+    );
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert_eq!(
+        xx::Message0::from_bytes(&longer).err(),
+        Some(MessageError::Trailing(0, 1))
+    );
+    let mut version = bytes.clone();
+    version[0] = 0xff;
+    assert_eq!(
+        xx::Message0::from_bytes(&version).err(),
+        Some(MessageError::Version(0, 0xff))
+    );
+    let mut number = bytes;
+    number[1] = 3;
+    assert_eq!(
+        xx::Message0::from_bytes(&number).err(),
+        Some(MessageError::Number(0, 3))
+    );
+    assert_eq!(
+        xx::Message3::from_bytes(&number, max).err(),
+        Some(MessageError::Truncated(3))
+    );
+
+    // the length of the payload is checked before the payload is read
+    let mut payload = vec![xx::WIRE_VERSION, 3];
+    // the ciphertext is 1152 bytes
+    payload.extend_from_slice(&[0; 1152]);
+    payload.extend_from_slice(&0x401u16.to_be_bytes());
+    assert_eq!(
+        xx::Message3::from_bytes(&payload, max).err(),
+        Some(MessageError::PayloadLength(3, 0x401, max)),
+    );
+    payload.extend_from_slice(&[0; 0x401 + 16]);
+    assert!(xx::Message3::from_bytes(&payload, 0x401).is_ok());
+
+    // the length of the outgoing payload should fit in 2 bytes
+    let (r_pk, r_sk) = PublicKey::gen(&Array::generate(|i| !i as u8));
+    let long = vec![0; xx::MAX_PAYLOAD + 1];
+    let seed = Line::clone_array(&Array::<typenum::U128>::generate(|i| i as u8));
+    match xx::take0_out1(&seed, &r_pk.identity(), &r_pk, &r_sk, message, long) {
+        Err(xx::RespondersError::Payload(length)) => assert_eq!(length, xx::MAX_PAYLOAD + 1),
+        _ => panic!("too long payload is truncated"),
+    }
+}
+
+#[test]
+fn malformed_keys() {
+    let (pk, _) = PublicKey::gen(&Array::generate(|i| i as u8));
+    let bytes = |elliptic: [u8; 32]| {
+        let Concat(_, lattice) = pk.compress();
+        Concat(Array::clone_from_slice(&elliptic), lattice)
+    };
+    let mut invalid = [0; 32];
+    invalid[0] = 2;
+    let mut identity = [0; 32];
+    identity[0] = 1;
+    // the point of order 2, its y is -1
+    let mut small_order = [0xff; 32];
+    small_order[0] = 0xec;
+    small_order[31] = 0x7f;
+
+    assert!(PublicKey::decompress(pk.compress()).is_ok());
+    assert_eq!(
+        PublicKey::decompress(bytes(invalid)).err(),
+        Some(KeyError::InvalidPoint)
+    );
+    assert_eq!(
+        PublicKey::decompress(bytes(identity)).err(),
+        Some(KeyError::IdentityPoint)
+    );
+    assert_eq!(
+        PublicKey::decompress(bytes(small_order)).err(),
+        Some(KeyError::SmallOrderPoint)
+    );
+
+    // the responder rejects the message instead of panic
+    let (r_pk, r_sk) = PublicKey::gen(&Array::generate(|i| !i as u8));
+    for (elliptic, error) in [
+        (invalid, KeyError::InvalidPoint),
+        (small_order, KeyError::SmallOrderPoint),
+    ]
+    .iter()
+    {
+        let message = xx::Message0(bytes(*elliptic)).to_bytes();
+        let responder = Responder::new(r_sk.clone(), r_pk.clone(), Payloads::default());
+        match responder.step(&message) {
+            Err(HandshakeError::Responder(xx::RespondersError::EphemeralKey(e))) => {
+                assert_eq!(e, *error)
+            },
+            _ => panic!("malformed key is accepted"),
+        }
+    }
+}
+
+#[test]
+fn identity_text() {
+    for i in 0..16u8 {
+        let (pk, _) = PublicKey::gen(&Array::generate(|j| (j as u8) ^ i));
+        let text = pk.identity().to_string();
+        assert_eq!(text.len(), 48);
+        assert_eq!(text.parse::<Identity>(), Ok(pk.identity()));
+    }
+
+    let (pk, _) = PublicKey::gen(&Array::generate(|i| i as u8));
+    let text = pk.identity().to_string();
+    let parse = |text: &str| text.parse::<Identity>().err();
+
+    assert!(matches!(
+        parse("not base64!"),
+        Some(IdentityError::Base64(_))
+    ));
+    assert_eq!(parse(""), Some(IdentityError::Length(0)));
+    assert_eq!(parse("AQID"), Some(IdentityError::Length(3)));
+    assert_eq!(parse(&text[..44]), Some(IdentityError::Length(33)));
+    // the old format, the leading byte is zero and no checksum
+    assert_eq!(parse(&"A".repeat(44)), Some(IdentityError::Version(0)));
+    let mut bytes = base64::decode(&text).unwrap();
+    bytes[0] = 2;
+    assert_eq!(
+        parse(&base64::encode(&bytes)),
+        Some(IdentityError::Version(2))
+    );
+
+    // a typo in any character is detected
+    for i in 0..text.len() {
+        let mut typo = text.clone().into_bytes();
+        typo[i] = if typo[i] == b'A' { b'B' } else { b'A' };
+        let typo = String::from_utf8(typo).unwrap();
+        assert!(
+            typo.parse::<Identity>().is_err(),
+            "typo is accepted: {}",
+            typo
+        );
+    }
+}
+
+#[test]
+fn short_auth_string() {
+    let (a_pk, _) = PublicKey::gen(&Array::generate(|i| i as u8));
+    let (b_pk, _) = PublicKey::gen(&Array::generate(|i| !i as u8));
+    let (a, b) = (a_pk.identity(), b_pk.identity());
+    let hash = [0x5a; 32];
+
+    let sas = ShortAuthString::new(&hash, &a, &b);
+    assert_eq!(sas, ShortAuthString::new(&hash, &b, &a));
+    assert_ne!(sas, ShortAuthString::new(&[0xa5; 32], &a, &b));
+
+    let text = sas.to_string();
+    assert_eq!(text.len(), 11);
+    assert!(text.split(' ').all(|group| group.len() == 3));
+}
+
+// the message goes to the other side until both sides are established
+fn run_state_machines(
+    initiator: Initiator,
+    message: Vec<u8>,
+    responder: Responder,
+) -> [Established; 2] {
+    let mut sides = [
+        Some(Handshake::Initiator(initiator)),
+        Some(Handshake::Responder(responder)),
+    ];
+    let mut established = [None, None];
+    let mut message = Some((0, message));
+    let mut side = 1;
+    while let Some((number, bytes)) = message.take() {
+        let handshake = sides[side].take().unwrap();
+        assert_eq!(handshake.expect_next(), number);
+        let Step {
+            message: next_message,
+            next,
+        } = handshake.step(&bytes).unwrap();
+        match next {
+            Next::Handshake(handshake) => sides[side] = Some(*handshake),
+            Next::Established(e) => established[side] = Some(*e),
+        }
+        message = next_message;
+        side ^= 1;
+    }
+    let [i, r] = established;
+    [i.unwrap(), r.unwrap()]
+}
+
+#[test]
+fn state_machines() {
+    let (i_pk, i_sk) = PublicKey::gen(&Array::generate(|i| i as u8));
+    let (r_pk, r_sk) = PublicKey::gen(&Array::generate(|i| !i as u8));
+
+    let (initiator, message) =
+        Initiator::new(i_sk, i_pk.clone(), &r_pk.identity(), Payloads::default());
+    let responder = Responder::new(r_sk, r_pk.clone(), Payloads::default());
+    let [i, r] = run_state_machines(initiator, message, responder);
+    let (
+        Established {
+            cipher: mut i_cipher,
+            hash: i_hash,
+            peer: ir_pk,
+            payloads: i_payloads,
+        },
+        Established {
+            cipher: mut r_cipher,
+            hash: r_hash,
+            peer: ri_pk,
+            payloads: r_payloads,
+        },
+    ) = (i, r);
+    assert_eq!(i_hash, r_hash);
+    assert_eq!(ir_pk.identity(), r_pk.identity());
+    assert_eq!(ri_pk.identity(), i_pk.identity());
+    assert!(i_payloads
+        .iter()
+        .chain(r_payloads.iter())
+        .all(Vec::is_empty));
+
+    let orig = rand::random::<[u8; 32]>();
+    let mut a = orig;
+    let tag = i_cipher.encrypt(b"vru", a.as_mut());
+    r_cipher.decrypt(b"vru", a.as_mut(), &tag).unwrap();
+    assert_eq!(orig, a);
+}
+
+#[test]
+fn state_machine_payloads() {
+    let (i_pk, i_sk) = PublicKey::gen(&Array::generate(|i| i as u8));
+    let (r_pk, r_sk) = PublicKey::gen(&Array::generate(|i| !i as u8));
+
+    let payloads = |first: &[u8], second: &[u8]| {
+        Payloads::new(first.to_vec(), second.to_vec(), 0x100).unwrap()
+    };
+    let (initiator, message) = Initiator::new(
+        i_sk.clone(),
+        i_pk.clone(),
+        &r_pk.identity(),
+        payloads(b"", &[0x13; 0x100]),
+    );
+    let responder = Responder::new(r_sk.clone(), r_pk.clone(), payloads(b"hello", b"world"));
+    let [i, r] = run_state_machines(initiator, message, responder);
+    assert_eq!(i.payloads, [b"hello".to_vec(), b"world".to_vec()]);
+    assert_eq!(r.payloads, [Vec::new(), vec![0x13; 0x100]]);
+
+    assert!(matches!(
+        Payloads::new(vec![0; xx::MAX_PAYLOAD + 1], Vec::new(), xx::MAX_PAYLOAD),
+        Err(HandshakeError::Payload(_)),
+    ));
+
+    // the responder accepts at most 0x100 bytes
+    let (initiator, message) =
+        Initiator::new(i_sk, i_pk, &r_pk.identity(), payloads(&[0x13; 0x101], b""));
+    let responder = Responder::new(r_sk, r_pk, payloads(b"", b""));
+    let Step { message, next } = responder.step(&message).unwrap();
+    let (_, message) = initiator
+        .step(&message.unwrap().1)
+        .unwrap()
+        .message
+        .unwrap();
+    match next {
+        Next::Handshake(responder) => match responder.step(&message) {
+            Err(HandshakeError::Message(MessageError::PayloadLength(2, 0x101, 0x100))) => (),
+            _ => panic!("too long payload is accepted"),
+        },
+        Next::Established(_) => panic!("the responder is established too early"),
+    }
+}
+
+#[test]
+fn state_machine_errors() {
+    let (i_pk, i_sk) = PublicKey::gen(&Array::generate(|i| i as u8));
+    let (r_pk, r_sk) = PublicKey::gen(&Array::generate(|i| !i as u8));
+
+    let responder = Responder::new(r_sk.clone(), r_pk.clone(), Payloads::default());
+    match responder.step(&[0; 16]) {
+        Err(HandshakeError::Message(MessageError::Version(0, 0))) => (),
+        _ => panic!("message with wrong version is accepted"),
+    }
+    let responder = Responder::new(r_sk.clone(), r_pk.clone(), Payloads::default());
+    match responder.step(&[xx::WIRE_VERSION, 0, 0]) {
+        Err(HandshakeError::Message(MessageError::Truncated(0))) => (),
+        _ => panic!("short message is accepted"),
+    }
+
+    // the responder answers to the initiator who expects someone else
+    let (other_pk, _) = PublicKey::gen(&Array::generate(|i| (i * 3) as u8));
+    let (initiator, message) =
+        Initiator::new(i_sk, i_pk, &other_pk.identity(), Payloads::default());
+    let responder = Responder::new(r_sk, r_pk, Payloads::default());
+    let (_, message) = responder.step(&message).unwrap().message.unwrap();
+    match initiator.step(&message) {
+        Err(HandshakeError::Initiator(_)) => (),
+        _ => panic!("the responder is not authenticated"),
+    }
+}
@@ -1,33 +1,40 @@
 use vru_noise::{SymmetricState, MacMismatch, ChainingKey, Key, Cipher, Rotor};
-use rac::{Array, Concat, LineValid, Line, generic_array::typenum};
+use rac::{
+    Array, Concat, LineValid, Line,
+    generic_array::typenum::{self, Unsigned},
+};
 use thiserror::Error;
 use super::{
-    key::{Identity, PublicKey, PublicKeyBytes, SecretKey, Ct},
-    noise::{Noise, EncryptedDefault, SymmetricStateOps},
+    key::{Identity, PublicKey, PublicKeyBytes, SecretKey, KeyError, Ct},
+    noise::{Noise, EncryptedDefault, EncryptedPayloadDefault, SymmetricStateOps},
 };
 
 // PublicKey = 1120
 // Ct = 1152
+// the payload is (2 + length + 16)
 
 // 1120
-pub type Message0 = PublicKeyBytes;
+pub struct Message0(pub PublicKeyBytes);
 
-// (1152 + p + 16) + 1120 + (1120 + 16)
-pub type Message1<P> = (
-    Concat<Ct, EncryptedDefault<P>>,
-    PublicKeyBytes,
-    EncryptedDefault<PublicKeyBytes>,
+// 1152 + p + 1120 + (1120 + 16)
+pub struct Message1(
+    pub Ct,
+    pub EncryptedPayloadDefault,
+    pub PublicKeyBytes,
+    pub EncryptedDefault<PublicKeyBytes>,
 );
 
-// (1152 + p + 16) + 1152 + (1120 + 16)
-pub type Message2<Q, R> = (
-    Concat<Ct, EncryptedDefault<Q>>,
-    Ct,
-    Concat<EncryptedDefault<PublicKeyBytes>, EncryptedDefault<R>>,
+// 1152 + q + 1152 + (1120 + 16) + r
+pub struct Message2(
+    pub Ct,
+    pub EncryptedPayloadDefault,
+    pub Ct,
+    pub EncryptedDefault<PublicKeyBytes>,
+    pub EncryptedPayloadDefault,
 );
 
-// (1152 + p + 16)
-pub type Message3<S> = Concat<Ct, EncryptedDefault<S>>;
+// 1152 + s
+pub struct Message3(pub Ct, pub EncryptedPayloadDefault);
 
 pub struct InitiatorsEphemeral {
     symmetric_state: SymmetricState<Noise, ChainingKey<Noise>>,
@@ -47,22 +54,285 @@ pub struct InitiatorsFinal {
 
 #[derive(Debug, Error)]
 pub enum InitiatorsError {
+    #[error("payload too long: {}, maximum: {}", _0, MAX_PAYLOAD)]
+    Payload(usize),
     #[error("payload_p {}", _0)]
     PayloadPMac(MacMismatch),
     #[error("payload_s {}", _0)]
     PayloadSMac(MacMismatch),
     #[error("static key {}", _0)]
     StaticKeyMac(MacMismatch),
+    #[error("ephemeral key {}", _0)]
+    EphemeralKey(KeyError),
+    #[error("static key {}", _0)]
+    StaticKey(KeyError),
 }
 
 #[derive(Debug, Error)]
 pub enum RespondersError {
+    #[error("payload too long: {}, maximum: {}", _0, MAX_PAYLOAD)]
+    Payload(usize),
     #[error("payload_q {}", _0)]
     PayloadQMac(MacMismatch),
     #[error("payload_r {}", _0)]
     PayloadRMac(MacMismatch),
     #[error("static key {}", _0)]
     StaticKeyMac(MacMismatch),
+    #[error("ephemeral key {}", _0)]
+    EphemeralKey(KeyError),
+    #[error("static key {}", _0)]
+    StaticKey(KeyError),
+}
+
+// the wire format of a message is the version and the number of the message
+// followed by the fields in the order of declaration,
+// the length of the payload (2 bytes, big endian) precedes the payload
+pub const WIRE_VERSION: u8 = 2;
+
+const HEADER_SIZE: usize = 2;
+
+// the length should fit in 2 bytes
+pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+// the handshake message should fit in a few datagrams
+pub const DEFAULT_MAX_PAYLOAD: usize = 0x400;
+
+// the size of the message with the given number, each of its payloads takes `payload` bytes
+pub const fn message_size(number: u8, payload: usize) -> usize {
+    let pk = <PublicKeyBytes as LineValid>::Length::USIZE;
+    let ct = <Ct as LineValid>::Length::USIZE;
+    let encrypted_pk = <EncryptedDefault<PublicKeyBytes> as LineValid>::Length::USIZE;
+    let payload = 2 + payload + (encrypted_pk - pk);
+    HEADER_SIZE
+        + match number {
+            0 => pk,
+            1 => ct + payload + pk + encrypted_pk,
+            2 => ct + payload + ct + encrypted_pk + payload,
+            _ => ct + payload,
+        }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum MessageError {
+    #[error("message {} is truncated", _0)]
+    Truncated(u8),
+    #[error("message {} has {} extra bytes", _0, _1)]
+    Trailing(u8, usize),
+    #[error("message {} payload length {}, maximum: {}", _0, _1, _2)]
+    PayloadLength(u8, usize, usize),
+    #[error("message {} version {}, expected: {}", _0, _1, WIRE_VERSION)]
+    Version(u8, u8),
+    #[error("message {}, expected: {}", _1, _0)]
+    Number(u8, u8),
+}
+
+pub trait Message
+where
+    Self: Sized,
+{
+    const NUMBER: u8;
+
+    fn to_bytes(&self) -> Vec<u8>;
+}
+
+// the messages 1, 2 and 3 carry payloads
+pub trait PayloadMessage
+where
+    Self: Message,
+{
+    // rejects the payload longer than `max_payload`
+    fn from_bytes(bytes: &[u8], max_payload: usize) -> Result<Self, MessageError>;
+}
+
+impl Message for Message0 {
+    const NUMBER: u8 = 0;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let Message0(a) = self;
+        Writer::new(Self::NUMBER).line(a).finish()
+    }
+}
+
+impl Message0 {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(Self::NUMBER, bytes)?;
+        let message = Message0(reader.line()?);
+        reader.finish().map(|()| message)
+    }
+}
+
+impl Message for Message1 {
+    const NUMBER: u8 = 1;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let Message1(a, b, c, d) = self;
+        Writer::new(Self::NUMBER)
+            .line(a)
+            .payload(b)
+            .line(c)
+            .line(d)
+            .finish()
+    }
+}
+
+impl PayloadMessage for Message1 {
+    fn from_bytes(bytes: &[u8], max_payload: usize) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(Self::NUMBER, bytes)?;
+        let message = Message1(
+            reader.line()?,
+            reader.payload(max_payload)?,
+            reader.line()?,
+            reader.line()?,
+        );
+        reader.finish().map(|()| message)
+    }
+}
+
+impl Message for Message2 {
+    const NUMBER: u8 = 2;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let Message2(a, b, c, d, e) = self;
+        Writer::new(Self::NUMBER)
+            .line(a)
+            .payload(b)
+            .line(c)
+            .line(d)
+            .payload(e)
+            .finish()
+    }
+}
+
+impl PayloadMessage for Message2 {
+    fn from_bytes(bytes: &[u8], max_payload: usize) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(Self::NUMBER, bytes)?;
+        let message = Message2(
+            reader.line()?,
+            reader.payload(max_payload)?,
+            reader.line()?,
+            reader.line()?,
+            reader.payload(max_payload)?,
+        );
+        reader.finish().map(|()| message)
+    }
+}
+
+impl Message for Message3 {
+    const NUMBER: u8 = 3;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let Message3(a, b) = self;
+        Writer::new(Self::NUMBER).line(a).payload(b).finish()
+    }
+}
+
+impl PayloadMessage for Message3 {
+    fn from_bytes(bytes: &[u8], max_payload: usize) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(Self::NUMBER, bytes)?;
+        let message = Message3(reader.line()?, reader.payload(max_payload)?);
+        reader.finish().map(|()| message)
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn new(number: u8) -> Self {
+        Writer(vec![WIRE_VERSION, number])
+    }
+
+    fn line<L>(mut self, line: &L) -> Self
+    where
+        L: LineValid,
+    {
+        self.0.extend_from_slice(&line.clone_line());
+        self
+    }
+
+    // the length is at most `MAX_PAYLOAD`, the functions which take payloads check it
+    fn payload(mut self, payload: &EncryptedPayloadDefault) -> Self {
+        self.0
+            .extend_from_slice(&(payload.data.len() as u16).to_be_bytes());
+        self.0.extend_from_slice(&payload.data);
+        self.0.extend_from_slice(&payload.tag);
+        self
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+struct Reader<'a> {
+    number: u8,
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(number: u8, bytes: &'a [u8]) -> Result<Self, MessageError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(MessageError::Truncated(number));
+        }
+        if bytes[0] != WIRE_VERSION {
+            return Err(MessageError::Version(number, bytes[0]));
+        }
+        if bytes[1] != number {
+            return Err(MessageError::Number(number, bytes[1]));
+        }
+        Ok(Reader {
+            number,
+            bytes: &bytes[HEADER_SIZE..],
+        })
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], MessageError> {
+        if self.bytes.len() < length {
+            return Err(MessageError::Truncated(self.number));
+        }
+        let (field, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(field)
+    }
+
+    fn line<L>(&mut self) -> Result<L, MessageError>
+    where
+        L: Line,
+    {
+        self.take(L::Length::USIZE)
+            .map(|field| L::clone_array(Array::from_slice(field)))
+    }
+
+    fn payload(&mut self, max_payload: usize) -> Result<EncryptedPayloadDefault, MessageError> {
+        let mut length = [0; 2];
+        length.clone_from_slice(self.take(2)?);
+        let length = u16::from_be_bytes(length) as usize;
+        if length > max_payload {
+            return Err(MessageError::PayloadLength(
+                self.number,
+                length,
+                max_payload,
+            ));
+        }
+        let data = self.take(length)?.to_vec();
+        let tag = self.line()?;
+        Ok(EncryptedPayloadDefault { data, tag })
+    }
+
+    fn finish(self) -> Result<(), MessageError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(MessageError::Trailing(self.number, self.bytes.len()))
+        }
+    }
+}
+
+fn check_payload(payload: &[u8]) -> Result<(), usize> {
+    if payload.len() > MAX_PAYLOAD {
+        Err(payload.len())
+    } else {
+        Ok(())
+    }
 }
 
 // the handshake variant is xx, but the initiator know responder pk
@@ -87,29 +357,22 @@ pub fn out0(seed: &Array<typenum::U96>, peer_s_pi: &Identity) -> (InitiatorsEphe
             e_pk,
             e_sk,
         },
-        e_pkc,
+        Message0(e_pkc),
     )
 }
 
-pub fn take1_out2<P, Q, R>(
+pub fn take1_out2(
     seed: &Concat<Array<typenum::U32>, Array<typenum::U32>>,
     state: InitiatorsEphemeral,
     s_pk: &PublicKey,
     s_sk: &SecretKey,
-    message: Message1<P>,
-    payload_q: Q,
-    payload_r: R,
-) -> Result<(InitiatorsFinal, PublicKey, P, Message2<Q, R>), InitiatorsError>
-where
-    P: Line,
-    EncryptedDefault<P>: Line,
-    Q: Line,
-    EncryptedDefault<Q>: Line,
-    R: Line,
-    EncryptedDefault<R>: Line,
-    EncryptedDefault<PublicKeyBytes>: Line,
-{
-    let (Concat(peer_e_ct, payload_p), peer_e_pkc, enc_peer_s_pkc) = message;
+    message: Message1,
+    payload_q: Vec<u8>,
+    payload_r: Vec<u8>,
+) -> Result<(InitiatorsFinal, PublicKey, Vec<u8>, Message2), InitiatorsError> {
+    check_payload(&payload_q).map_err(InitiatorsError::Payload)?;
+    check_payload(&payload_r).map_err(InitiatorsError::Payload)?;
+    let Message1(peer_e_ct, payload_p, peer_e_pkc, enc_peer_s_pkc) = message;
     let InitiatorsEphemeral {
         symmetric_state,
         e_pk,
@@ -118,15 +381,15 @@ where
 
     let (symmetric_state, payload_p) = symmetric_state
         .mix_shared_secret(&e_pk.decapsulate(&e_sk, &peer_e_ct))
-        .decrypt_line(payload_p)
+        .decrypt_payload(payload_p)
         .map_err(InitiatorsError::PayloadPMac)?;
     let symmetric_state = symmetric_state.mix_hash(&peer_e_pkc.clone_line());
-    let peer_e_pk = PublicKey::decompress(peer_e_pkc);
+    let peer_e_pk = PublicKey::decompress(peer_e_pkc).map_err(InitiatorsError::EphemeralKey)?;
     let (symmetric_state, peer_s_pkc) = symmetric_state
         .mix_shared_secret(&peer_e_pk.dh(&e_sk))
         .decrypt_line(enc_peer_s_pkc)
         .map_err(InitiatorsError::StaticKeyMac)?;
-    let peer_s_pk = PublicKey::decompress(peer_s_pkc);
+    let peer_s_pk = PublicKey::decompress(peer_s_pkc).map_err(InitiatorsError::StaticKey)?;
     let peer_e_pq;
     let symmetric_state = symmetric_state
         .mix_shared_secret(&peer_s_pk.dh(&e_sk))
@@ -134,7 +397,7 @@ where
             peer_e_pq = peer_e_pk.encapsulate(&seed.0);
             &peer_e_pq.ss
         });
-    let (symmetric_state, payload_q) = symmetric_state.encrypt_line(payload_q);
+    let (symmetric_state, payload_q) = symmetric_state.encrypt_payload(payload_q);
     let peer_s_pq;
     let s_pkc = s_pk.compress();
     let (symmetric_state, enc_s_pkc) = symmetric_state
@@ -145,37 +408,31 @@ where
         .encrypt_line(s_pkc);
     let (symmetric_state, payload_r) = symmetric_state
         .mix_shared_secret(&peer_e_pk.dh(&s_sk))
-        .encrypt_line(payload_r);
+        .encrypt_payload(payload_r);
 
     Ok((
         InitiatorsFinal { symmetric_state },
         peer_s_pk,
         payload_p,
-        (
-            Concat(peer_e_pq.ct, payload_q),
-            peer_s_pq.ct,
-            Concat(enc_s_pkc, payload_r),
-        ),
+        Message2(peer_e_pq.ct, payload_q, peer_s_pq.ct, enc_s_pkc, payload_r),
     ))
 }
 
-pub fn take_3<S, Z>(
+pub fn take_3<Z>(
     state: InitiatorsFinal,
     s_pk: &PublicKey,
     s_sk: &SecretKey,
-    message: Message3<S>,
-) -> Result<(Cipher<Noise, Z>, Array<typenum::U32>, S), InitiatorsError>
+    message: Message3,
+) -> Result<(Cipher<Noise, Z>, Array<typenum::U32>, Vec<u8>), InitiatorsError>
 where
-    S: Line,
-    EncryptedDefault<S>: Line,
     Z: Rotor<Noise>,
 {
-    let Concat(peer_s_ct, payload_s) = message;
+    let Message3(peer_s_ct, payload_s) = message;
     let InitiatorsFinal { symmetric_state } = state;
 
     let (symmetric_state, payload_s) = symmetric_state
         .mix_shared_secret(&s_pk.decapsulate(&s_sk, &peer_s_ct))
-        .decrypt_line(payload_s)
+        .decrypt_payload(payload_s)
         .map_err(InitiatorsError::PayloadSMac)?;
     let (cipher, hash) = symmetric_state.finish();
 
@@ -184,32 +441,28 @@ where
 
 ////////////
 
-pub fn take0_out1<P>(
+pub fn take0_out1(
     seed: &Concat<Array<typenum::U96>, Array<typenum::U32>>,
     s_pi: &Identity,
     s_pk: &PublicKey,
     s_sk: &SecretKey,
     message: Message0,
-    payload_p: P,
-) -> (RespondersEphemeral, Message1<P>)
-where
-    P: Line,
-    EncryptedDefault<P>: Line,
-    EncryptedDefault<PublicKeyBytes>: Line,
-{
-    let peer_e_pkc = message;
+    payload_p: Vec<u8>,
+) -> Result<(RespondersEphemeral, Message1), RespondersError> {
+    check_payload(&payload_p).map_err(RespondersError::Payload)?;
+    let Message0(peer_e_pkc) = message;
 
     let symmetric_state = SymmetricState::<Noise, _>::new("Noise_XX_25519+Kyber_ChaChaPoly_SHA256")
         .mix_hash(&s_pi.as_ref())
         .mix_hash(&peer_e_pkc.clone_line());
-    let peer_e_pk = PublicKey::decompress(peer_e_pkc);
+    let peer_e_pk = PublicKey::decompress(peer_e_pkc).map_err(RespondersError::EphemeralKey)?;
     let peer_e_pq;
     let (symmetric_state, payload_p) = symmetric_state
         .mix_shared_secret({
             peer_e_pq = peer_e_pk.encapsulate(&seed.1);
             &peer_e_pq.ss
         })
-        .encrypt_line(payload_p);
+        .encrypt_payload(payload_p);
     let (e_pk, e_sk) = PublicKey::gen(&seed.0);
     let e_pkc = e_pk.compress();
     let s_pkc = s_pk.compress();
@@ -219,36 +472,30 @@ where
         .encrypt_line(s_pkc);
     let symmetric_state = symmetric_state.mix_shared_secret(&peer_e_pk.dh(&s_sk));
 
-    (
+    Ok((
         RespondersEphemeral {
             symmetric_state,
             e_pk,
             e_sk,
         },
-        (Concat(peer_e_pq.ct, payload_p), e_pkc, enc_s_pkc),
-    )
+        Message1(peer_e_pq.ct, payload_p, e_pkc, enc_s_pkc),
+    ))
 }
 
 #[rustfmt::skip]
-pub fn take2_out3<Q, R, S, Z>(
+pub fn take2_out3<Z>(
     seed: &Array<typenum::U32>,
     state: RespondersEphemeral,
     s_pk: &PublicKey,
     s_sk: &SecretKey,
-    message: Message2<Q, R>,
-    payload_s: S,
-) -> Result<(Cipher<Noise, Z>, Array<typenum::U32>, PublicKey, Q, R, Message3<S>), RespondersError>
+    message: Message2,
+    payload_s: Vec<u8>,
+) -> Result<(Cipher<Noise, Z>, Array<typenum::U32>, PublicKey, Vec<u8>, Vec<u8>, Message3), RespondersError>
 where
-    Q: Line,
-    EncryptedDefault<Q>: Line,
-    R: Line,
-    EncryptedDefault<R>: Line,
-    S: Line,
-    EncryptedDefault<S>: Line,
-    EncryptedDefault<PublicKeyBytes>: Line,
     Z: Rotor<Noise>,
 {
-    let (Concat(peer_e_ct, payload_q), peer_s_ct, Concat(enc_peer_s_pkc, payload_r)) = message;
+    check_payload(&payload_s).map_err(RespondersError::Payload)?;
+    let Message2(peer_e_ct, payload_q, peer_s_ct, enc_peer_s_pkc, payload_r) = message;
     let RespondersEphemeral {
         symmetric_state,
         e_pk,
@@ -257,16 +504,16 @@ where
 
     let (symmetric_state, payload_q) = symmetric_state
         .mix_shared_secret(&e_pk.decapsulate(&e_sk, &peer_e_ct))
-        .decrypt_line(payload_q)
+        .decrypt_payload(payload_q)
         .map_err(RespondersError::PayloadQMac)?;
     let (symmetric_state, peer_s_pkc) = symmetric_state
         .mix_shared_secret(&s_pk.decapsulate(&s_sk, &peer_s_ct))
         .decrypt_line(enc_peer_s_pkc)
         .map_err(RespondersError::StaticKeyMac)?;
-    let peer_s_pk = PublicKey::decompress(peer_s_pkc);
+    let peer_s_pk = PublicKey::decompress(peer_s_pkc).map_err(RespondersError::StaticKey)?;
     let (symmetric_state, payload_r) = symmetric_state
         .mix_shared_secret(&peer_s_pk.dh(&e_sk))
-        .decrypt_line(payload_r)
+        .decrypt_payload(payload_r)
         .map_err(RespondersError::PayloadRMac)?;
     let peer_s_pq;
     let (symmetric_state, payload_s) = symmetric_state
@@ -274,7 +521,7 @@ where
             peer_s_pq = peer_s_pk.encapsulate(&seed);
             &peer_s_pq.ss
         })
-        .encrypt_line(payload_s);
+        .encrypt_payload(payload_s);
     let (cipher, hash) = symmetric_state.finish();
 
     Ok((
@@ -283,6 +530,6 @@ where
         peer_s_pk,
         payload_q,
         payload_r,
-        Concat(peer_s_pq.ct, payload_s),
+        Message3(peer_s_pq.ct, payload_s),
     ))
 }
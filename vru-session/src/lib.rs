@@ -5,6 +5,8 @@ pub mod handshake;
 
 mod node;
 mod processor;
+mod sessions;
 
-pub use self::node::{Command, Event, NodeDisconnected, NodeRef, Node};
-pub use self::processor::{ProcessorFactory, Processor};
+pub use self::node::{Command, Reply, PeerInfo, Status, Event, NodeDisconnected, NodeRef, Node};
+pub use self::processor::{ProcessorFactory, Processor, Outgoing, PeerDisconnected};
+pub use self::sessions::{SessionHandle, Sessions, Pending, PendingSlot};
@@ -1,8 +1,11 @@
-use std::sync::{Arc, atomic::AtomicBool};
+use std::{
+    sync::{Arc, atomic::AtomicBool},
+    time::SystemTime,
+};
 use thiserror::Error;
 use serde::{Serialize, Deserialize};
 use super::{
-    handshake::{SecretKey, PublicKey, Identity},
+    handshake::{SecretKey, PublicKey, Identity, ShortAuthString},
     processor::ProcessorFactory,
 };
 
@@ -20,21 +23,69 @@ pub enum Command<A> {
         destination: Identity,
         command: Vec<u8>,
     },
+    Identity,
+    ListPeers,
+    Status,
+    Disconnect {
+        peer_pi: Identity,
+    },
+    AuthString {
+        peer_pi: Identity,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Reply<A> {
+    Done,
+    Identity(Identity),
+    Peers(Vec<PeerInfo<A>>),
+    Status(Status<A>),
+    AuthString(ShortAuthString),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo<A> {
+    pub identity: Identity,
+    pub address: A,
+    pub transport: String,
+    pub incoming: bool,
+    pub handshake_time: SystemTime,
+    // the length of application messages, without encryption and framing
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status<A> {
+    pub identity: Identity,
+    pub address: A,
+    pub transport: String,
+    pub peers: usize,
+    pub start_time: SystemTime,
 }
 
 #[derive(Debug)]
-pub enum Event<E> {
+pub enum Event<E, A> {
     Error(E),
     DebugInfo(String),
     Local {
         source: Box<PublicKey>,
         local: Vec<u8>,
     },
+    // the remote address of the session
+    HandshakeDone {
+        peer: Box<PublicKey>,
+        address: A,
+        incoming: bool,
+    },
+    Disconnected {
+        peer: Identity,
+    },
 }
 
-pub trait NodeRef<E> {
-    fn recv(&self) -> Result<Event<E>, NodeDisconnected>;
-    fn try_recv(&self) -> Result<Option<Event<E>>, NodeDisconnected>;
+pub trait NodeRef<E, A> {
+    fn recv(&self) -> Result<Event<E, A>, NodeDisconnected>;
+    fn try_recv(&self) -> Result<Option<Event<E, A>>, NodeDisconnected>;
 }
 
 pub trait Node<P>
@@ -43,7 +94,7 @@ where
     P: ProcessorFactory,
 {
     type Error;
-    type Ref: NodeRef<Self::Error>;
+    type Ref: NodeRef<Self::Error, Self::Address>;
     type Address;
 
     fn spawn(
@@ -54,7 +105,12 @@ where
         running: Arc<AtomicBool>,
     ) -> Result<(Self, Self::Ref), Self::Error>;
 
-    fn command(&self, command: Command<Self::Address>);
+    // the error is about the command itself, for example, there is no session with the peer,
+    // failures which happen later are reported as events
+    fn command(&self, command: Command<Self::Address>) -> Result<Reply<Self::Address>, Self::Error>;
+
+    // the node refuses incoming sessions while it has `max` sessions, outgoing are not limited
+    fn limit_incoming(&self, max: usize);
 
     fn join(self);
 }
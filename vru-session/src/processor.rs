@@ -1,4 +1,6 @@
-use super::handshake::Identity;
+use std::fmt;
+use thiserror::Error;
+use super::handshake::{Identity, PublicKey};
 
 pub trait ProcessorFactory {
     type Processor: Processor;
@@ -6,7 +8,39 @@ pub trait ProcessorFactory {
     fn spawn_processor(&mut self, peer_pi: Option<Identity>) -> Self::Processor;
 }
 
+// the transport calls the processor from the thread which serves the peer
 pub trait Processor {
+    fn handshake_done(&mut self, peer: &PublicKey, hash: &[u8], outgoing: Outgoing);
+
+    fn message(&mut self, message: Vec<u8>);
+
+    fn disconnected(&mut self);
+}
+
+#[derive(Debug, Error)]
+#[error("peer disconnected")]
+pub struct PeerDisconnected;
+
+// the capability to send a message to the peer, the transport encrypts the message
+pub struct Outgoing(Box<dyn Fn(Vec<u8>) -> Result<(), PeerDisconnected> + Send>);
+
+impl Outgoing {
+    pub fn new<F>(send: F) -> Self
+    where
+        F: Fn(Vec<u8>) -> Result<(), PeerDisconnected> + Send + 'static,
+    {
+        Outgoing(Box::new(send))
+    }
+
+    pub fn send(&self, message: Vec<u8>) -> Result<(), PeerDisconnected> {
+        (self.0)(message)
+    }
+}
+
+impl fmt::Debug for Outgoing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Outgoing").finish()
+    }
 }
 
 impl ProcessorFactory for () {
@@ -14,8 +48,17 @@ impl ProcessorFactory for () {
 
     fn spawn_processor(&mut self, peer_pi: Option<Identity>) -> Self::Processor {
         let _ = peer_pi;
-        ()
     }
 }
 
-impl Processor for () {}
+impl Processor for () {
+    fn handshake_done(&mut self, peer: &PublicKey, hash: &[u8], outgoing: Outgoing) {
+        let _ = (peer, hash, outgoing);
+    }
+
+    fn message(&mut self, message: Vec<u8>) {
+        let _ = message;
+    }
+
+    fn disconnected(&mut self) {}
+}
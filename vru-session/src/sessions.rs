@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use super::{
+    handshake::{Identity, ShortAuthString},
+    node::PeerInfo,
+    processor::PeerDisconnected,
+};
+
+// the node holds the handle of each established session, the worker serves the session
+pub trait SessionHandle {
+    type Address;
+
+    fn send(&self, data: Vec<u8>) -> Result<(), PeerDisconnected>;
+
+    // the worker closes the session and reports the disconnection
+    fn close(&self) -> Result<(), PeerDisconnected>;
+
+    fn info(&self, identity: &Identity) -> PeerInfo<Self::Address>;
+
+    fn auth_string(&self) -> ShortAuthString;
+
+    // the handles of the same session
+    fn same(&self, other: &Self) -> bool;
+}
+
+// the established sessions by the identity of the peer, shared by the node and the workers,
+// a clone refers to the same table, so it might close sessions from another thread
+pub struct Sessions<H>(Arc<Mutex<HashMap<Identity, H>>>);
+
+impl<H> Clone for Sessions<H> {
+    fn clone(&self) -> Self {
+        Sessions(self.0.clone())
+    }
+}
+
+impl<H> Default for Sessions<H> {
+    fn default() -> Self {
+        Sessions(Arc::new(Mutex::new(HashMap::new())))
+    }
+}
+
+impl<H> Sessions<H>
+where
+    H: SessionHandle,
+{
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, peer: &Identity) -> bool {
+        self.0.lock().unwrap().contains_key(peer)
+    }
+
+    // returns false if there is no session with the peer
+    pub fn send(&self, peer: &Identity, data: Vec<u8>) -> bool {
+        let sessions = self.0.lock().unwrap();
+        sessions.get(peer).map(|handle| handle.send(data).is_ok()) == Some(true)
+    }
+
+    // returns false if there is no session with the peer
+    pub fn disconnect(&self, peer: &Identity) -> bool {
+        let sessions = self.0.lock().unwrap();
+        sessions.get(peer).map(|handle| handle.close().is_ok()) == Some(true)
+    }
+
+    pub fn info(&self) -> Vec<PeerInfo<H::Address>> {
+        let sessions = self.0.lock().unwrap();
+        sessions
+            .iter()
+            .map(|(identity, handle)| handle.info(identity))
+            .collect()
+    }
+
+    pub fn auth_string(&self, peer: &Identity) -> Option<ShortAuthString> {
+        self.0.lock().unwrap().get(peer).map(H::auth_string)
+    }
+
+    // the limit is checked under the same lock, returns false if the limit is reached
+    pub fn insert(&self, peer: Identity, handle: H, limit: usize) -> bool {
+        let mut sessions = self.0.lock().unwrap();
+        if sessions.len() >= limit {
+            return false;
+        }
+        sessions.insert(peer, handle);
+        true
+    }
+
+    // the peer might have a newer session, it is kept
+    pub fn remove(&self, peer: &Identity, handle: &H) {
+        let mut sessions = self.0.lock().unwrap();
+        if sessions.get(peer).map(|h| h.same(handle)) == Some(true) {
+            sessions.remove(peer);
+        }
+    }
+}
+
+// counts the responders which are in the middle of the handshake
+#[derive(Clone, Default)]
+pub struct Pending(Arc<AtomicUsize>);
+
+// the responder holds the slot until the handshake is done or failed
+pub struct PendingSlot(Arc<AtomicUsize>);
+
+impl Pending {
+    pub fn acquire(&self, max: usize) -> Option<PendingSlot> {
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                if count < max {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| PendingSlot(self.0.clone()))
+    }
+}
+
+impl Drop for PendingSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, mpsc},
+        time::SystemTime,
+    };
+    use rac::{Array, generic_array::typenum};
+    use crate::{
+        PeerDisconnected, PeerInfo,
+        handshake::{Identity, PublicKey, ShortAuthString},
+    };
+    use super::{SessionHandle, Sessions};
+
+    #[derive(Clone)]
+    struct Handle(Arc<mpsc::Sender<Vec<u8>>>);
+
+    impl SessionHandle for Handle {
+        type Address = ();
+
+        fn send(&self, data: Vec<u8>) -> Result<(), PeerDisconnected> {
+            self.0.send(data).map_err(|_| PeerDisconnected)
+        }
+
+        fn close(&self) -> Result<(), PeerDisconnected> {
+            Ok(())
+        }
+
+        fn info(&self, identity: &Identity) -> PeerInfo<()> {
+            PeerInfo {
+                identity: identity.clone(),
+                address: (),
+                transport: "test".to_string(),
+                incoming: false,
+                handshake_time: SystemTime::now(),
+                bytes_in: 0,
+                bytes_out: 0,
+            }
+        }
+
+        fn auth_string(&self) -> ShortAuthString {
+            unreachable!()
+        }
+
+        fn same(&self, other: &Self) -> bool {
+            Arc::ptr_eq(&self.0, &other.0)
+        }
+    }
+
+    fn identity(seed: u8) -> Identity {
+        let (pk, _) = PublicKey::gen(&Array::<typenum::U96>::clone_from_slice(&[seed; 96]));
+        pk.identity()
+    }
+
+    #[test]
+    fn limit_and_replace() {
+        let sessions = Sessions::default();
+        let (a, b) = (identity(1), identity(2));
+        let (tx, rx) = mpsc::channel();
+        let old = Handle(Arc::new(tx.clone()));
+        assert!(sessions.insert(a.clone(), old.clone(), 1));
+        assert!(!sessions.insert(b.clone(), Handle(Arc::new(tx.clone())), 1));
+        assert!(!sessions.send(&b, b"lost".to_vec()));
+
+        // the newer session with the same peer is not removed by the old worker
+        let new = Handle(Arc::new(tx));
+        assert!(sessions.insert(a.clone(), new.clone(), usize::MAX));
+        sessions.remove(&a, &old);
+        assert!(sessions.send(&a, b"new".to_vec()));
+        assert_eq!(rx.recv().unwrap(), b"new");
+        sessions.remove(&a, &new);
+        assert!(sessions.is_empty());
+    }
+}
@@ -1,30 +1,57 @@
 #![allow(unused_variables, dead_code)]
 
-use std::{collections::HashMap, io, thread, net::SocketAddr, sync::{Arc, atomic::AtomicBool, mpsc}};
+mod peer;
+pub use self::peer::PeerHandle;
+use self::peer::Peer;
+
+use std::{cell::RefCell, collections::HashMap, io, mem, thread, net::SocketAddr, sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc}, time::SystemTime};
 use thiserror::Error;
 use mio::{Poll, Waker, net::{TcpListener, TcpStream}};
 use vru_session::{
     self as session,
     Command,
+    Reply,
+    Status,
     Event,
     NodeDisconnected,
-    handshake::{PublicKey, SecretKey, Identity},
+    Sessions,
+    Pending,
+    handshake::{PublicKey, SecretKey, Identity, HandshakeError},
 };
 
-pub struct NodeRef(mpsc::Receiver<Event<NodeError>>);
+// the accepted streams which are in the middle of the handshake
+const MAX_PENDING: usize = 16;
+
+pub struct NodeRef(mpsc::Receiver<Event<NodeError, SocketAddr>>);
 
 #[derive(Debug, Error)]
 pub enum NodeError {
     #[error("io error: {}", _0)]
     Io(io::Error),
+    #[error("frame size {}, address: {}", _1, _0)]
+    FrameSize(SocketAddr, usize),
+    #[error("mac mismatch, address: {}", _0)]
+    MacMismatch(SocketAddr),
+    #[error("connect error: {}, address: {}", _1, _0)]
+    Connect(SocketAddr, io::Error),
+    #[error("already connected, identity: {}", _0)]
+    AlreadyConnected(Identity),
+    #[error("no session, identity: {}", _0)]
+    NoSession(Identity),
+    #[error("too many peers, refuse incoming session, address: {}", _0)]
+    TooManyPeers(SocketAddr),
+    #[error("handshake error: {}, address: {}", _1, _0)]
+    Handshake(SocketAddr, HandshakeError),
+    #[error("handshake timeout, address: {}", _0)]
+    HandshakeTimeout(SocketAddr),
 }
 
-impl session::NodeRef<NodeError> for NodeRef {
-    fn recv(&self) -> Result<Event<NodeError>, NodeDisconnected> {
+impl session::NodeRef<NodeError, SocketAddr> for NodeRef {
+    fn recv(&self) -> Result<Event<NodeError, SocketAddr>, NodeDisconnected> {
         self.0.recv().map_err(|mpsc::RecvError| NodeDisconnected)
     }
 
-    fn try_recv(&self) -> Result<Option<Event<NodeError>>, NodeDisconnected> {
+    fn try_recv(&self) -> Result<Option<Event<NodeError, SocketAddr>>, NodeDisconnected> {
         self.0.try_recv()
             .map(Some)
             .or_else(|error| match error {
@@ -38,18 +65,24 @@ pub struct Node<P>
 where
     P: session::ProcessorFactory,
 {
+    sk: SecretKey,
+    pk: PublicKey,
+    address: SocketAddr,
+    start_time: SystemTime,
     main_thread: thread::JoinHandle<()>,
     waker: Waker,
-    sender: mpsc::Sender<Event<NodeError>>,
-    incoming: mpsc::Receiver<(SocketAddr, Peer<P::Processor>)>,
-    peers: HashMap<Identity, Peer<P::Processor>>,
-    processor_factory: P,
+    running: Arc<AtomicBool>,
+    sender: mpsc::Sender<Event<NodeError, SocketAddr>>,
+    peers: RefCell<HashMap<Identity, Peer>>,
+    sessions: Sessions<PeerHandle>,
+    max_incoming: Arc<AtomicUsize>,
+    processor_factory: RefCell<P>,
 }
 
 impl<P> session::Node<P> for Node<P>
 where
     P: session::ProcessorFactory + Clone + Send + 'static,
-    P::Processor: Send,
+    P::Processor: Send + 'static,
 {
     type Error = NodeError;
     type Ref = NodeRef;
@@ -65,57 +98,151 @@ where
         use mio::{Interest, Token};
 
         let (sender, rx) = mpsc::channel();
-        let (peer_tx, peer_rx) = mpsc::channel();
+        let sessions = Sessions::default();
+        let max_incoming = Arc::new(AtomicUsize::new(usize::MAX));
 
         let poll = Poll::new().map_err(NodeError::Io)?;
         let waker = Waker::new(poll.registry(), Token(0)).map_err(NodeError::Io)?;
 
+        let mut listener = TcpListener::bind(address).map_err(NodeError::Io)?;
+        let address = listener.local_addr().map_err(NodeError::Io)?;
         let main_thread = {
-            let mut listener = TcpListener::bind(address).map_err(NodeError::Io)?;
-
             poll.registry().register(&mut listener, Token(1), Interest::READABLE)
                 .map_err(NodeError::Io)?;
 
             let state = NodeState::<P> {
-                sk,
-                pk,
+                sk: sk.clone(),
+                pk: pk.clone(),
                 listener,
                 poll,
                 sender: sender.clone(),
-                incoming: peer_tx,
+                incoming: Vec::new(),
+                sessions: sessions.clone(),
+                max_incoming: max_incoming.clone(),
+                pending: Pending::default(),
                 processor_factory: processor_factory.clone(),
             };
             thread::Builder::new()
                 .name("node-main".to_string())
-                .spawn(move || state.run(running))
+                .spawn({
+                    let running = running.clone();
+                    move || state.run(running)
+                })
                 .expect("failed to spawn main thread")
         };
 
         Ok((
             Node {
+                sk,
+                pk,
+                address,
+                start_time: SystemTime::now(),
                 main_thread,
                 waker,
+                running,
                 sender,
-                incoming: peer_rx,
-                peers: HashMap::new(),
-                processor_factory,
+                peers: RefCell::new(HashMap::new()),
+                sessions,
+                max_incoming,
+                processor_factory: RefCell::new(processor_factory),
             },
             NodeRef(rx),
         ))
     }
 
-    fn command(&self, command: Command<Self::Address>) {
+    fn command(&self, command: Command<Self::Address>) -> Result<Reply<Self::Address>, Self::Error> {
         match command {
             Command::Connect { peer_pi, address } => {
-                let stream = TcpStream::connect(address).unwrap();
+                self.connect(peer_pi, address).map(|()| Reply::Done)
+            },
+            Command::Local {
+                destination,
+                command,
+            } => {
+                if !self.sessions.send(&destination, command) {
+                    return Err(NodeError::NoSession(destination));
+                }
+                Ok(Reply::Done)
+            },
+            Command::Identity => Ok(Reply::Identity(self.pk.identity())),
+            Command::ListPeers => Ok(Reply::Peers(self.sessions.info())),
+            Command::Status => Ok(Reply::Status(Status {
+                identity: self.pk.identity(),
+                address: self.address,
+                transport: "tcp".to_string(),
+                peers: self.sessions.len(),
+                start_time: self.start_time,
+            })),
+            Command::AuthString { peer_pi } => match self.sessions.auth_string(&peer_pi) {
+                Some(auth_string) => Ok(Reply::AuthString(auth_string)),
+                None => Err(NodeError::NoSession(peer_pi)),
+            },
+            Command::Disconnect { peer_pi } => {
+                if !self.sessions.disconnect(&peer_pi) {
+                    return Err(NodeError::NoSession(peer_pi));
+                }
+                Ok(Reply::Done)
             },
-            _ => (),
         }
     }
 
+    fn limit_incoming(&self, max: usize) {
+        self.max_incoming.store(max, Ordering::Release);
+    }
+
     fn join(self) {
         self.waker.wake().unwrap();
-        self.main_thread.join().unwrap()
+        self.main_thread.join().unwrap();
+        for (_, peer) in self.peers.into_inner() {
+            peer.join();
+        }
+    }
+}
+
+impl<P> Node<P>
+where
+    P: session::ProcessorFactory,
+    P::Processor: Send + 'static,
+{
+    pub fn sessions(&self) -> Sessions<PeerHandle> {
+        self.sessions.clone()
+    }
+
+    fn connect(&self, peer_pi: Identity, address: SocketAddr) -> Result<(), NodeError> {
+        if self.sessions.contains(&peer_pi) {
+            return Err(NodeError::AlreadyConnected(peer_pi));
+        }
+        let mut peers = self.peers.borrow_mut();
+        if let Some(peer) = peers.get(&peer_pi) {
+            if !peer.is_finished() {
+                return Err(NodeError::AlreadyConnected(peer_pi));
+            }
+            if let Some(peer) = peers.remove(&peer_pi) {
+                peer.join();
+            }
+        }
+
+        let stream = TcpStream::connect(address).map_err(|error| NodeError::Connect(address, error))?;
+        let processor = self
+            .processor_factory
+            .borrow_mut()
+            .spawn_processor(Some(peer_pi.clone()));
+        let peer = Peer::spawn(
+            self.sk.clone(),
+            self.pk.clone(),
+            stream,
+            address,
+            Some(&peer_pi),
+            None,
+            processor,
+            self.sessions.clone(),
+            self.max_incoming.clone(),
+            self.sender.clone(),
+            self.running.clone(),
+        )
+        .map_err(|error| NodeError::Connect(address, error))?;
+        peers.insert(peer_pi, peer);
+        Ok(())
     }
 }
 
@@ -127,17 +254,29 @@ where
     pk: PublicKey,
     listener: TcpListener,
     poll: Poll,
-    sender: mpsc::Sender<Event<NodeError>>,
-    incoming: mpsc::Sender<(SocketAddr, Peer<P::Processor>)>,
+    sender: mpsc::Sender<Event<NodeError, SocketAddr>>,
+    // the workers of accepted streams, finished ones are joined in the poll loop
+    incoming: Vec<Peer>,
+    sessions: Sessions<PeerHandle>,
+    max_incoming: Arc<AtomicUsize>,
+    pending: Pending,
     processor_factory: P,
 }
 
 impl<P> NodeState<P>
 where
     P: session::ProcessorFactory,
+    P::Processor: Send + 'static,
 {
     fn run(mut self, running: Arc<AtomicBool>) {
-        use std::{time::Duration, sync::atomic::Ordering};
+        self.run_loop(&running);
+        for peer in self.incoming.drain(..) {
+            peer.join();
+        }
+    }
+
+    fn run_loop(&mut self, running: &Arc<AtomicBool>) {
+        use std::time::Duration;
         use mio::Events;
 
         let mut events = Events::with_capacity(2);
@@ -155,6 +294,7 @@ where
                     Err(error) => self.report(Event::Error(NodeError::Io(error))),
                 }
             }
+            self.join_finished();
 
             for event in &events {
                 match event.token().0 {
@@ -162,19 +302,15 @@ where
                         log::info!("wake");
                         return;
                     },
-                    1 => match self.listener.accept() {
-                        Ok((stream, address)) => {
-                            let processor = self.processor_factory.spawn_processor(None);
-                            let peer = Peer::spawn(
-                                self.sk.clone(),
-                                self.pk.clone(),
-                                stream,
-                                processor,
-                                running.clone(),
-                            );
-                            self.incoming.send((address, peer)).unwrap();
-                        },
-                        Err(error) => self.report(Event::Error(NodeError::Io(error))),
+                    1 => loop {
+                        match self.listener.accept() {
+                            Ok((stream, address)) => self.accept(stream, address, running),
+                            Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(error) => {
+                                self.report(Event::Error(NodeError::Io(error)));
+                                break;
+                            },
+                        }
                     },
                     _ => unreachable!(),
                 }
@@ -182,39 +318,52 @@ where
         }
     }
 
-    fn report(&self, event: Event<NodeError>) {
-        match self.sender.send(event) {
-            Ok(()) => (),
-            Err(mpsc::SendError(event)) => log::warn!("failed to send event: {:?}", event),
+    fn accept(&mut self, stream: TcpStream, address: SocketAddr, running: &Arc<AtomicBool>) {
+        // the stream is dropped before the worker is spawned
+        if self.sessions.len() >= self.max_incoming.load(Ordering::Acquire) {
+            self.report(Event::Error(NodeError::TooManyPeers(address)));
+            return;
+        }
+        let pending = match self.pending.acquire(MAX_PENDING) {
+            Some(v) => v,
+            None => {
+                log::debug!("drop stream from {}, too many pending handshakes", address);
+                return;
+            },
+        };
+        let processor = self.processor_factory.spawn_processor(None);
+        match Peer::spawn(
+            self.sk.clone(),
+            self.pk.clone(),
+            stream,
+            address,
+            None,
+            Some(pending),
+            processor,
+            self.sessions.clone(),
+            self.max_incoming.clone(),
+            self.sender.clone(),
+            running.clone(),
+        ) {
+            Ok(peer) => self.incoming.push(peer),
+            Err(error) => self.report(Event::Error(NodeError::Io(error))),
         }
     }
-}
 
-struct Peer<P>
-where
-    P: session::Processor,
-{
-    worker_thread: thread::JoinHandle<()>,
-    processor: P,
-    waker: Waker,
-    sender: mpsc::Sender<Vec<u8>>,
-}
-
-impl<P> Peer<P>
-where
-    P: session::Processor,
-{
-    fn spawn(
-        sk: SecretKey,
-        pk: PublicKey,
-        stream: TcpStream,
-        processor: P,
-        running: Arc<AtomicBool>,
-    ) -> Self {
-        unimplemented!()
+    fn join_finished(&mut self) {
+        let (finished, alive) = mem::take(&mut self.incoming)
+            .into_iter()
+            .partition::<Vec<_>, _>(Peer::is_finished);
+        self.incoming = alive;
+        for peer in finished {
+            peer.join();
+        }
     }
 
-    fn join(self) {
-        self.worker_thread.join().unwrap()
+    fn report(&self, event: Event<NodeError, SocketAddr>) {
+        match self.sender.send(event) {
+            Ok(()) => (),
+            Err(mpsc::SendError(event)) => log::warn!("failed to send event: {:?}", event),
+        }
     }
 }
@@ -0,0 +1,480 @@
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{
+        Arc, mpsc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use mio::{Events, Interest, Poll, Token, Waker, net::TcpStream};
+use vru_session::{
+    self as session, Event, Outgoing, PeerDisconnected, PeerInfo, PendingSlot, SessionHandle,
+    Sessions,
+    handshake::{
+        PublicKey, SecretKey, Identity, ShortAuthString, TrivialCipher, Handshake, Initiator,
+        Responder, Payloads, Step, Next, Established,
+    },
+};
+use super::NodeError;
+
+// the length of the frame (4 bytes, big endian) precedes the frame
+const FRAME_HEADER: usize = 4;
+
+const MAX_FRAME: usize = 1 << 16;
+
+const TAG_SIZE: usize = 16;
+
+// the worker closes the stream if the handshake is not done in time
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const STREAM: Token = Token(0);
+const WAKER: Token = Token(1);
+
+pub struct Peer {
+    worker_thread: thread::JoinHandle<()>,
+    waker: Arc<Waker>,
+}
+
+impl Peer {
+    // the peer is the initiator if the identity of the remote peer is known,
+    // in this case the stream might be still connecting;
+    // the handle is registered in `sessions` when the handshake is done
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn<P>(
+        sk: SecretKey,
+        pk: PublicKey,
+        stream: TcpStream,
+        address: SocketAddr,
+        peer_pi: Option<&Identity>,
+        pending: Option<PendingSlot>,
+        processor: P,
+        sessions: Sessions<PeerHandle>,
+        max_incoming: Arc<AtomicUsize>,
+        event_sender: mpsc::Sender<Event<NodeError, SocketAddr>>,
+        running: Arc<AtomicBool>,
+    ) -> io::Result<Self>
+    where
+        P: session::Processor + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        let mut stream = stream;
+        let poll = Poll::new()?;
+        poll.registry()
+            .register(&mut stream, STREAM, Interest::READABLE | Interest::WRITABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+        let mut write_buffer = Vec::new();
+        let handshake = match peer_pi {
+            Some(peer_pi) => {
+                let (initiator, message) =
+                    Initiator::new(sk, pk.clone(), peer_pi, Payloads::default());
+                push_frame(&mut write_buffer, &message);
+                Handshake::Initiator(initiator)
+            },
+            None => Handshake::Responder(Responder::new(sk, pk.clone(), Payloads::default())),
+        };
+        let state = PeerState {
+            pk,
+            stream,
+            address,
+            poll,
+            receiver,
+            processor,
+            waker: waker.clone(),
+            sender,
+            handle: None,
+            sessions,
+            max_incoming,
+            event_sender,
+            incoming: peer_pi.is_none(),
+            connecting: peer_pi.is_some(),
+            state: Some(State::Handshake(Box::new(handshake))),
+            deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+            pending,
+            read_buffer: Vec::new(),
+            write_buffer,
+        };
+        let worker_thread = thread::Builder::new()
+            .name("node-worker".to_string())
+            .spawn(move || state.run(running))
+            .expect("failed to spawn thread");
+
+        Ok(Peer {
+            worker_thread,
+            waker,
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.worker_thread.is_finished()
+    }
+
+    pub fn join(self) {
+        let _ = self.waker.wake();
+        self.worker_thread.join().unwrap()
+    }
+}
+
+enum PeerMessage {
+    Data(Vec<u8>),
+    Close,
+}
+
+// the handle of the established session
+#[derive(Clone)]
+pub struct PeerHandle {
+    waker: Arc<Waker>,
+    sender: mpsc::Sender<PeerMessage>,
+    stats: Arc<Stats>,
+}
+
+struct Stats {
+    address: SocketAddr,
+    incoming: bool,
+    handshake_time: SystemTime,
+    auth_string: ShortAuthString,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl SessionHandle for PeerHandle {
+    type Address = SocketAddr;
+
+    fn send(&self, message: Vec<u8>) -> Result<(), PeerDisconnected> {
+        self.message(PeerMessage::Data(message))
+    }
+
+    // the worker shuts down the stream, the disconnection is reported as the event
+    fn close(&self) -> Result<(), PeerDisconnected> {
+        self.message(PeerMessage::Close)
+    }
+
+    fn info(&self, identity: &Identity) -> PeerInfo<SocketAddr> {
+        PeerInfo {
+            identity: identity.clone(),
+            address: self.stats.address,
+            transport: "tcp".to_string(),
+            incoming: self.stats.incoming,
+            handshake_time: self.stats.handshake_time,
+            bytes_in: self.stats.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.stats.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+
+    fn auth_string(&self) -> ShortAuthString {
+        self.stats.auth_string
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.stats, &other.stats)
+    }
+}
+
+impl PeerHandle {
+    fn message(&self, message: PeerMessage) -> Result<(), PeerDisconnected> {
+        self.sender.send(message).map_err(|_| PeerDisconnected)?;
+        self.waker.wake().map_err(|_| PeerDisconnected)
+    }
+}
+
+enum State {
+    Handshake(Box<Handshake>),
+    Done(TrivialCipher, Box<PublicKey>),
+}
+
+struct PeerState<P> {
+    pk: PublicKey,
+    stream: TcpStream,
+    address: SocketAddr,
+    poll: Poll,
+    receiver: mpsc::Receiver<PeerMessage>,
+    processor: P,
+    waker: Arc<Waker>,
+    sender: mpsc::Sender<PeerMessage>,
+    handle: Option<PeerHandle>,
+    sessions: Sessions<PeerHandle>,
+    max_incoming: Arc<AtomicUsize>,
+    event_sender: mpsc::Sender<Event<NodeError, SocketAddr>>,
+    incoming: bool,
+    connecting: bool,
+    state: Option<State>,
+    deadline: Instant,
+    pending: Option<PendingSlot>,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+}
+
+impl<P> PeerState<P>
+where
+    P: session::Processor,
+{
+    fn run(mut self, running: Arc<AtomicBool>) {
+        self.run_loop(running);
+
+        // the session is not available anymore, unless it was replaced by a newer one
+        if let (Some(State::Done(_, peer_pk)), Some(handle)) = (&self.state, &self.handle)
+        {
+            let identity = peer_pk.identity();
+            self.sessions.remove(&identity, handle);
+            self.processor.disconnected();
+            self.report(Event::Disconnected { peer: identity });
+        }
+    }
+
+    fn run_loop(&mut self, running: Arc<AtomicBool>) {
+        let mut events = Events::with_capacity(4);
+
+        while running.load(Ordering::Acquire) {
+            match self.poll.poll(&mut events, Some(Duration::from_secs(1))) {
+                Ok(()) => (),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => break,
+                Err(error) => {
+                    self.report(Event::Error(NodeError::Io(error)));
+                    break;
+                },
+            }
+            if matches!(self.state, Some(State::Handshake(_))) && Instant::now() > self.deadline {
+                self.report(Event::Error(NodeError::HandshakeTimeout(self.address)));
+                break;
+            }
+
+            for event in &events {
+                let result = match event.token() {
+                    STREAM => self.ready(event.is_readable(), event.is_writable()),
+                    WAKER => self.outgoing(),
+                    _ => unreachable!(),
+                };
+                match result {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        log::info!("connection with {} closed", self.address);
+                        return;
+                    },
+                    Err(error) => {
+                        self.report(Event::Error(error));
+                        return;
+                    },
+                }
+            }
+        }
+    }
+
+    // returns false if the connection is closed
+    fn ready(&mut self, readable: bool, writable: bool) -> Result<bool, NodeError> {
+        if self.connecting {
+            let address = self.address;
+            if let Some(error) = self.stream.take_error().map_err(NodeError::Io)? {
+                return Err(NodeError::Connect(address, error));
+            }
+            match self.stream.peer_addr() {
+                Ok(_) => self.connecting = false,
+                Err(error) if error.kind() == io::ErrorKind::NotConnected => return Ok(true),
+                Err(error) => return Err(NodeError::Connect(address, error)),
+            }
+        }
+        if writable {
+            self.flush()?;
+        }
+        if readable {
+            self.read()
+        } else {
+            Ok(true)
+        }
+    }
+
+    // returns false if the connection is closed
+    fn read(&mut self) -> Result<bool, NodeError> {
+        let mut buffer = [0; 0x1000];
+        loop {
+            // complete frames are taken after each read, so the buffer holds
+            // at most one incomplete frame, its length is checked before the body is read
+            let free = (FRAME_HEADER + MAX_FRAME - self.read_buffer.len()).min(buffer.len());
+            match self.stream.read(&mut buffer[..free]) {
+                Ok(0) => return Ok(false),
+                Ok(length) => {
+                    self.read_buffer.extend_from_slice(&buffer[..length]);
+                    self.frames()?;
+                },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+                Err(error) => return Err(NodeError::Io(error)),
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn frames(&mut self) -> Result<(), NodeError> {
+        while self.read_buffer.len() >= FRAME_HEADER {
+            let mut length = [0; FRAME_HEADER];
+            length.clone_from_slice(&self.read_buffer[..FRAME_HEADER]);
+            let length = u32::from_be_bytes(length) as usize;
+            if length > MAX_FRAME {
+                return Err(NodeError::FrameSize(self.address, length));
+            }
+            if self.read_buffer.len() < FRAME_HEADER + length {
+                break;
+            }
+            let frame = self.read_buffer[FRAME_HEADER..(FRAME_HEADER + length)].to_vec();
+            self.read_buffer.drain(..(FRAME_HEADER + length));
+            self.frame(frame)?;
+        }
+
+        Ok(())
+    }
+
+    fn frame(&mut self, frame: Vec<u8>) -> Result<(), NodeError> {
+        let state = self
+            .state
+            .take()
+            .expect("the worker stops when the handshake fails");
+        let state = match state {
+            State::Done(mut cipher, peer_pk) => {
+                let mut frame = frame;
+                if frame.len() < TAG_SIZE {
+                    return Err(NodeError::FrameSize(self.address, frame.len()));
+                }
+                cipher
+                    .decrypt_ext(b"", &mut frame)
+                    .map_err(|_| NodeError::MacMismatch(self.address))?;
+                if let Some(handle) = &self.handle {
+                    handle.stats.bytes_in.fetch_add(frame.len() as u64, Ordering::Relaxed);
+                }
+                self.processor.message(frame.clone());
+                self.report(Event::Local {
+                    source: peer_pk.clone(),
+                    local: frame,
+                });
+                State::Done(cipher, peer_pk)
+            },
+            State::Handshake(handshake) => self.take(*handshake, frame)?,
+        };
+        self.state = Some(state);
+        Ok(())
+    }
+
+    fn take(&mut self, handshake: Handshake, message: Vec<u8>) -> Result<State, NodeError> {
+        let address = self.address;
+        let Step { message, next } = handshake
+            .step(&message)
+            .map_err(|error| NodeError::Handshake(address, error))?;
+        if let Some((_, message)) = message {
+            self.write_frame(&message)?;
+        }
+        match next {
+            Next::Handshake(handshake) => Ok(State::Handshake(handshake)),
+            Next::Established(established) => {
+                let Established { cipher, hash, peer, .. } = *established;
+                self.done(&peer, &hash)?;
+                Ok(State::Done(cipher, Box::new(peer)))
+            },
+        }
+    }
+
+    fn done(&mut self, peer_pk: &PublicKey, hash: &[u8]) -> Result<(), NodeError> {
+        self.pending = None;
+        let handle = PeerHandle {
+            waker: self.waker.clone(),
+            sender: self.sender.clone(),
+            stats: Arc::new(Stats {
+                address: self.address,
+                incoming: self.incoming,
+                handshake_time: SystemTime::now(),
+                auth_string: ShortAuthString::new(hash, &self.pk.identity(), &peer_pk.identity()),
+                bytes_in: AtomicU64::new(0),
+                bytes_out: AtomicU64::new(0),
+            }),
+        };
+        let limit = if self.incoming {
+            self.max_incoming.load(Ordering::Acquire)
+        } else {
+            usize::MAX
+        };
+        if !self.sessions.insert(peer_pk.identity(), handle.clone(), limit) {
+            return Err(NodeError::TooManyPeers(self.address));
+        }
+        self.handle = Some(handle.clone());
+        let outgoing = Outgoing::new(move |message| handle.send(message));
+        self.processor.handshake_done(peer_pk, hash, outgoing);
+        log::info!("handshake done with {}, identity: {}", self.address, peer_pk.identity());
+        self.report(Event::HandshakeDone {
+            peer: Box::new(peer_pk.clone()),
+            address: self.address,
+            incoming: self.incoming,
+        });
+        Ok(())
+    }
+
+    // returns false if the connection is closed by the command
+    fn outgoing(&mut self) -> Result<bool, NodeError> {
+        while let Ok(message) = self.receiver.try_recv() {
+            let mut message = match message {
+                PeerMessage::Data(message) => message,
+                PeerMessage::Close => {
+                    let _ = self.flush();
+                    let _ = self.stream.shutdown(std::net::Shutdown::Both);
+                    return Ok(false);
+                },
+            };
+            if message.len() + TAG_SIZE > MAX_FRAME {
+                self.report(Event::Error(NodeError::FrameSize(self.address, message.len())));
+                continue;
+            }
+            match &mut self.state {
+                Some(State::Done(cipher, _)) => cipher.encrypt_ext(b"", &mut message),
+                _ => {
+                    log::warn!("handshake is not done, drop message");
+                    continue;
+                },
+            }
+            if let Some(handle) = &self.handle {
+                let length = (message.len() - TAG_SIZE) as u64;
+                handle.stats.bytes_out.fetch_add(length, Ordering::Relaxed);
+            }
+            self.write_frame(&message)?;
+        }
+        Ok(true)
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), NodeError> {
+        if frame.len() > MAX_FRAME {
+            return Err(NodeError::FrameSize(self.address, frame.len()));
+        }
+        push_frame(&mut self.write_buffer, frame);
+        if self.connecting {
+            Ok(())
+        } else {
+            self.flush()
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), NodeError> {
+        while !self.write_buffer.is_empty() {
+            match self.stream.write(&self.write_buffer) {
+                Ok(0) => return Err(NodeError::Io(io::ErrorKind::WriteZero.into())),
+                Ok(length) => {
+                    self.write_buffer.drain(..length);
+                },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => (),
+                Err(error) => return Err(NodeError::Io(error)),
+            }
+        }
+        Ok(())
+    }
+
+    fn report(&self, event: Event<NodeError, SocketAddr>) {
+        match self.event_sender.send(event) {
+            Ok(()) => (),
+            Err(mpsc::SendError(event)) => log::warn!("failed to send event: {:?}", event),
+        }
+    }
+}
+
+fn push_frame(buffer: &mut Vec<u8>, frame: &[u8]) {
+    buffer.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(frame);
+}
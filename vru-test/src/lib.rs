@@ -0,0 +1,230 @@
+#![forbid(unsafe_code)]
+
+// spawns several nodes on loopback in one process, for integration tests of the transports
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, atomic::{AtomicBool, Ordering}},
+    thread,
+    time::{Duration, Instant},
+};
+use rac::{Array, generic_array::typenum};
+use rand::Rng;
+use vru_session::{
+    Command, Reply, Event, Node, NodeRef,
+    handshake::{PublicKey, SecretKey, Identity},
+};
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn keys() -> (PublicKey, SecretKey) {
+    let mut seed = Array::<typenum::U96>::default();
+    rand::thread_rng().fill(seed.as_mut_slice());
+    PublicKey::gen(&seed)
+}
+
+pub struct TestNode<N>
+where
+    N: Node<()>,
+{
+    pk: PublicKey,
+    address: N::Address,
+    node: Option<N>,
+    node_ref: N::Ref,
+    running: Arc<AtomicBool>,
+}
+
+impl<N> TestNode<N>
+where
+    N: Node<(), Address = SocketAddr>,
+    N::Error: fmt::Debug,
+{
+    // listens on an ephemeral port on loopback, nodes of different transports
+    // might share the keys, dropping the node stops every node sharing `running`
+    pub fn spawn(pk: PublicKey, sk: SecretKey, running: &Arc<AtomicBool>) -> Self {
+        let address = ([127, 0, 0, 1], 0).into();
+        let (node, node_ref) = N::spawn(sk, pk.clone(), address, (), running.clone())
+            .unwrap_or_else(|error| panic!("failed to spawn node, error: {:?}", error));
+        let address = match node.command(Command::Status) {
+            Ok(Reply::Status(status)) => status.address,
+            reply => panic!("unexpected status: {:?}", reply),
+        };
+        TestNode {
+            pk,
+            address,
+            node: Some(node),
+            node_ref,
+            running: running.clone(),
+        }
+    }
+
+    pub fn identity(&self) -> Identity {
+        self.pk.identity()
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.pk
+    }
+
+    // the address the node actually listens on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn node(&self) -> &N {
+        self.node.as_ref().expect("the node is running")
+    }
+
+    pub fn command(&self, command: Command<SocketAddr>) -> Result<Reply<SocketAddr>, N::Error> {
+        self.node().command(command)
+    }
+
+    // connects to the node `responder` and waits the handshake on both sides
+    pub fn connect<M>(&self, responder: &TestNode<M>)
+    where
+        M: Node<(), Address = SocketAddr>,
+        M::Error: fmt::Debug,
+    {
+        let command = Command::Connect {
+            peer_pi: responder.identity(),
+            address: responder.address(),
+        };
+        self.command(command)
+            .unwrap_or_else(|error| panic!("failed to connect to {}, error: {:?}", responder.identity(), error));
+        let (incoming, address) = self.expect(TIMEOUT, "handshake", |event| match event {
+            Event::HandshakeDone { peer, address, incoming } if peer.identity() == responder.identity() => {
+                Some((incoming, address))
+            },
+            _ => None,
+        });
+        assert!(!incoming);
+        assert_eq!(address, responder.address());
+        assert!(responder.expect_handshake(&self.identity()));
+    }
+
+    // skips events which does not match, panics if no matching event in time
+    pub fn expect<F, T>(&self, timeout: Duration, what: &str, mut f: F) -> T
+    where
+        F: FnMut(Event<N::Error, SocketAddr>) -> Option<T>,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.node_ref.try_recv() {
+                Ok(Some(event)) => {
+                    if let Some(v) = f(event) {
+                        break v;
+                    }
+                },
+                Ok(None) => {
+                    if Instant::now() > deadline {
+                        panic!("timeout, {} expects: {}", self.identity(), what);
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                },
+                Err(error) => panic!("{} expects: {}, error: {}", self.identity(), what, error),
+            }
+        }
+    }
+
+    pub fn expect_handshake(&self, peer: &Identity) -> bool {
+        self.expect(TIMEOUT, "handshake", |event| match event {
+            Event::HandshakeDone { peer: pk, incoming, .. } if pk.identity() == *peer => Some(incoming),
+            _ => None,
+        })
+    }
+
+    pub fn expect_local(&self, source: &Identity) -> Vec<u8> {
+        self.expect(TIMEOUT, "local message", |event| match event {
+            Event::Local { source: pk, local } if pk.identity() == *source => Some(local),
+            _ => None,
+        })
+    }
+
+    pub fn expect_disconnected(&self, peer: &Identity) {
+        self.expect(TIMEOUT, "disconnect", |event| match event {
+            Event::Disconnected { peer: pi } if pi == *peer => Some(()),
+            _ => None,
+        })
+    }
+
+    // the remaining events, without waiting
+    pub fn drain(&self) -> Vec<Event<N::Error, SocketAddr>> {
+        let mut events = Vec::new();
+        while let Ok(Some(event)) = self.node_ref.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+}
+
+pub struct Harness<N>
+where
+    N: Node<()>,
+{
+    nodes: Vec<TestNode<N>>,
+}
+
+impl<N> Harness<N>
+where
+    N: Node<(), Address = SocketAddr>,
+    N::Error: fmt::Debug,
+{
+    // every node listens on its own ephemeral port on loopback
+    pub fn spawn(count: usize) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let nodes = (0..count)
+            .map(|_| {
+                let (pk, sk) = keys();
+                TestNode::spawn(pk, sk, &running)
+            })
+            .collect();
+
+        Harness { nodes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, i: usize) -> &TestNode<N> {
+        &self.nodes[i]
+    }
+
+    // connects the node `from` to the node `to` and waits the handshake on both sides
+    pub fn connect(&self, from: usize, to: usize) {
+        self.nodes[from].connect(&self.nodes[to]);
+    }
+
+    pub fn send(&self, from: usize, to: usize, message: &[u8]) -> Result<Reply<SocketAddr>, N::Error> {
+        let command = Command::Local {
+            destination: self.nodes[to].identity(),
+            command: message.to_vec(),
+        };
+        self.nodes[from].command(command)
+    }
+
+    // sends the message and waits it on the other side
+    pub fn deliver(&self, from: usize, to: usize, message: &[u8]) {
+        self.send(from, to, message)
+            .unwrap_or_else(|error| panic!("failed to send from {} to {}, error: {:?}", from, to, error));
+        let received = self.nodes[to].expect_local(&self.nodes[from].identity());
+        assert_eq!(received, message);
+    }
+}
+
+impl<N> Drop for TestNode<N>
+where
+    N: Node<()>,
+{
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(node) = self.node.take() {
+            node.join();
+        }
+    }
+}
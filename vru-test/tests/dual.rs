@@ -0,0 +1,93 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, atomic::AtomicBool},
+    thread,
+    time::{Duration, Instant},
+};
+use vru_session::{Command, Reply, Event, Node};
+use vru_test::{TestNode, TIMEOUT, keys};
+
+type Dual = vru_node::dual::DualNode<()>;
+type Tcp = vru_tcp::Node<()>;
+type Udp = vru_udp::Node<()>;
+
+// the peer runs a node of each transport with the same keys
+fn spawn() -> (TestNode<Dual>, TestNode<Tcp>, TestNode<Udp>) {
+    let running = Arc::new(AtomicBool::new(true));
+    let (pk, sk) = keys();
+    let dual = TestNode::spawn(pk, sk, &running);
+    let (pk, sk) = keys();
+    let tcp = TestNode::spawn(pk.clone(), sk.clone(), &running);
+    let udp = TestNode::spawn(pk, sk, &running);
+    (dual, tcp, udp)
+}
+
+// the dual node closes the duplicate and does not report it
+fn connect_duplicate<N>(peer: &TestNode<N>, dual: &TestNode<Dual>, kept: &str)
+where
+    N: Node<(), Address = SocketAddr>,
+    N::Error: fmt::Debug,
+{
+    let command = Command::Connect {
+        peer_pi: dual.identity(),
+        address: dual.address(),
+    };
+    peer.command(command).unwrap();
+    assert!(!peer.expect_handshake(&dual.identity()));
+
+    let deadline = Instant::now() + TIMEOUT;
+    while list_peers(dual) != [kept] {
+        assert!(Instant::now() < deadline, "the duplicate is not closed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    for event in dual.drain() {
+        assert!(!matches!(event, Event::HandshakeDone { .. }), "{:?}", event);
+    }
+}
+
+fn list_peers(dual: &TestNode<Dual>) -> Vec<String> {
+    match dual.command(Command::ListPeers) {
+        Ok(Reply::Peers(peers)) => peers.into_iter().map(|peer| peer.transport).collect(),
+        reply => panic!("unexpected reply: {:?}", reply),
+    }
+}
+
+#[test]
+fn duplicate_udp_session() {
+    let (dual, tcp, udp) = spawn();
+    tcp.connect(&dual);
+    connect_duplicate(&udp, &dual, "tcp");
+
+    dual.command(Command::Local {
+        destination: tcp.identity(),
+        command: b"over tcp".to_vec(),
+    })
+    .unwrap();
+    assert_eq!(tcp.expect_local(&dual.identity()), b"over tcp");
+
+    // once the kept session is closed, the other transport might take its place
+    tcp.command(Command::Disconnect { peer_pi: dual.identity() }).unwrap();
+    dual.expect_disconnected(&tcp.identity());
+    // the udp peer does not know its session is closed
+    udp.command(Command::Disconnect { peer_pi: dual.identity() }).unwrap();
+    udp.expect_disconnected(&dual.identity());
+    udp.connect(&dual);
+    assert_eq!(list_peers(&dual), ["udp"]);
+}
+
+#[test]
+fn duplicate_tcp_session() {
+    let (dual, tcp, udp) = spawn();
+    udp.connect(&dual);
+    connect_duplicate(&tcp, &dual, "udp");
+    // unlike udp, tcp tells the peer the session is closed
+    tcp.expect_disconnected(&dual.identity());
+
+    udp.command(Command::Local {
+        destination: dual.identity(),
+        command: b"over udp".to_vec(),
+    })
+    .unwrap();
+    assert_eq!(dual.expect_local(&udp.identity()), b"over udp");
+}
@@ -0,0 +1,262 @@
+use std::{
+    fmt,
+    net::{SocketAddr, TcpStream, UdpSocket},
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    thread,
+    time::Duration,
+};
+use vru_session::{Command, Reply, Event, Node};
+use vru_test::{Harness, TIMEOUT};
+
+type Tcp = vru_tcp::Node<()>;
+type Udp = vru_udp::Node<()>;
+
+fn exchange<N>()
+where
+    N: Node<(), Address = SocketAddr>,
+    N::Error: fmt::Debug,
+{
+    let harness = Harness::<N>::spawn(2);
+    harness.connect(0, 1);
+    harness.deliver(0, 1, b"hello");
+    harness.deliver(1, 0, b"world");
+}
+
+fn chain<N>()
+where
+    N: Node<(), Address = SocketAddr>,
+    N::Error: fmt::Debug,
+{
+    let harness = Harness::<N>::spawn(3);
+    harness.connect(0, 1);
+    harness.connect(1, 2);
+    harness.deliver(0, 1, b"first");
+    harness.deliver(2, 1, b"second");
+    harness.deliver(1, 2, b"third");
+
+    match harness.node(1).command(Command::ListPeers) {
+        Ok(Reply::Peers(peers)) => assert_eq!(peers.len(), 2),
+        reply => panic!("unexpected reply: {:?}", reply),
+    }
+    // no session between the ends of the chain
+    assert!(harness.send(0, 2, b"lost").is_err());
+}
+
+fn disconnect<N>()
+where
+    N: Node<(), Address = SocketAddr>,
+    N::Error: fmt::Debug,
+{
+    let harness = Harness::<N>::spawn(2);
+    harness.connect(0, 1);
+    let peer_pi = harness.node(1).identity();
+    harness.node(0).command(Command::Disconnect { peer_pi: peer_pi.clone() }).unwrap();
+    harness.node(0).expect_disconnected(&peer_pi);
+    assert!(harness.send(0, 1, b"late").is_err());
+}
+
+#[test]
+fn tcp_exchange() {
+    exchange::<Tcp>()
+}
+
+#[test]
+fn udp_exchange() {
+    exchange::<Udp>()
+}
+
+// much bigger than a datagram, but fits in a frame
+#[test]
+fn tcp_big_message() {
+    let harness = Harness::<Tcp>::spawn(2);
+    harness.connect(0, 1);
+    harness.deliver(0, 1, &[0x5a; 0x8000]);
+}
+
+// the client connects, but never starts the handshake
+#[test]
+fn tcp_silent_client() {
+    let harness = Harness::<Tcp>::spawn(1);
+    let _stream = TcpStream::connect(harness.node(0).address()).unwrap();
+    harness.node(0).expect(TIMEOUT * 2, "handshake timeout", |event| match event {
+        Event::Error(vru_tcp::NodeError::HandshakeTimeout(_)) => Some(()),
+        _ => None,
+    });
+}
+
+// the length is rejected before the frame is buffered
+#[test]
+fn tcp_oversized_frame() {
+    use std::io::Write;
+
+    let harness = Harness::<Tcp>::spawn(1);
+    let mut stream = TcpStream::connect(harness.node(0).address()).unwrap();
+    stream.write_all(&0x10001u32.to_be_bytes()).unwrap();
+    stream.write_all(&[0; 0x1000]).unwrap();
+    harness.node(0).expect(TIMEOUT, "frame size error", |event| match event {
+        Event::Error(vru_tcp::NodeError::FrameSize(_, 0x10001)) => Some(()),
+        _ => None,
+    });
+}
+
+// the message should fit in a datagram, the node reports the error
+#[test]
+fn udp_big_message() {
+    let harness = Harness::<Udp>::spawn(2);
+    harness.connect(0, 1);
+    harness.send(0, 1, &[0x5a; 4096]).unwrap();
+    harness.node(0).expect(TIMEOUT, "frame size error", |event| match event {
+        Event::Error(vru_udp::NodeError::FrameSize(_, 4096)) => Some(()),
+        _ => None,
+    });
+}
+
+// forwards datagrams between the client and the server, loses the first data datagram
+// from the client and swaps the next two
+struct LossyRelay {
+    address: SocketAddr,
+    // the last data datagram from the client, as an eavesdropper sees it
+    last: Arc<Mutex<Option<Vec<u8>>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl LossyRelay {
+    // the datagram is 1280 bytes, the kind follows the link token of 16 bytes
+    const DATA_KIND: (usize, u8) = (16, 1);
+
+    fn spawn(server: SocketAddr) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let address = socket.local_addr().unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+        let last = Arc::new(Mutex::new(None));
+        {
+            let running = running.clone();
+            let last = last.clone();
+            thread::spawn(move || {
+                let (mut client, mut count, mut held) = (None, 0, None);
+                let mut buffer = [0; 1280];
+                while running.load(Ordering::Acquire) {
+                    let (length, source) = match socket.recv_from(&mut buffer) {
+                        Ok(v) => v,
+                        Err(_) => continue,
+                    };
+                    let datagram = buffer[..length].to_vec();
+                    if source == server {
+                        if let Some(client) = client {
+                            socket.send_to(&datagram, client).unwrap();
+                        }
+                        continue;
+                    }
+                    client = Some(source);
+                    let (position, data) = Self::DATA_KIND;
+                    if datagram[position] == data {
+                        *last.lock().unwrap() = Some(datagram.clone());
+                        count += 1;
+                        match count {
+                            1 => continue,
+                            2 => {
+                                held = Some(datagram);
+                                continue;
+                            },
+                            3 => {
+                                socket.send_to(&datagram, server).unwrap();
+                                socket.send_to(&held.take().unwrap(), server).unwrap();
+                                continue;
+                            },
+                            _ => (),
+                        }
+                    }
+                    socket.send_to(&datagram, server).unwrap();
+                }
+            });
+        }
+        LossyRelay {
+            address,
+            last,
+            running,
+        }
+    }
+}
+
+impl Drop for LossyRelay {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+    }
+}
+
+#[test]
+fn udp_lost_and_reordered() {
+    let harness = Harness::<Udp>::spawn(2);
+    let relay = LossyRelay::spawn(harness.node(1).address());
+    let (client, server) = (harness.node(0), harness.node(1));
+    let command = Command::Connect {
+        peer_pi: server.identity(),
+        address: relay.address,
+    };
+    client.command(command).unwrap();
+    assert!(!client.expect_handshake(&server.identity()));
+    assert!(server.expect_handshake(&client.identity()));
+
+    harness.send(0, 1, b"lost").unwrap();
+    harness.send(0, 1, b"late").unwrap();
+    harness.send(0, 1, b"early").unwrap();
+    assert_eq!(server.expect_local(&client.identity()), b"early");
+    assert_eq!(server.expect_local(&client.identity()), b"late");
+    harness.deliver(0, 1, b"after");
+    harness.deliver(1, 0, b"answer");
+}
+
+// the link token is visible on the wire, but the session follows only authentic datagrams
+#[test]
+fn udp_spoofed_address() {
+    let harness = Harness::<Udp>::spawn(2);
+    let relay = LossyRelay::spawn(harness.node(1).address());
+    let (client, server) = (harness.node(0), harness.node(1));
+    let command = Command::Connect {
+        peer_pi: server.identity(),
+        address: relay.address,
+    };
+    client.command(command).unwrap();
+    assert!(!client.expect_handshake(&server.identity()));
+    assert!(server.expect_handshake(&client.identity()));
+    for message in [&b"lost"[..], b"late", b"early"] {
+        harness.send(0, 1, message).unwrap();
+    }
+    assert_eq!(server.expect_local(&client.identity()), b"early");
+    assert_eq!(server.expect_local(&client.identity()), b"late");
+
+    let mut datagram = relay.last.lock().unwrap().take().unwrap();
+    let attacker = UdpSocket::bind("127.0.0.1:0").unwrap();
+    attacker.send_to(&datagram, server.address()).unwrap();
+    *datagram.last_mut().unwrap() ^= 1;
+    attacker.send_to(&datagram, server.address()).unwrap();
+    datagram[30] ^= 1;
+    attacker.send_to(&datagram, server.address()).unwrap();
+
+    harness.deliver(1, 0, b"answer");
+    match server.command(Command::ListPeers) {
+        Ok(Reply::Peers(peers)) => assert_eq!(peers[0].address, relay.address),
+        reply => panic!("unexpected reply: {:?}", reply),
+    }
+}
+
+#[test]
+fn tcp_chain() {
+    chain::<Tcp>()
+}
+
+#[test]
+fn udp_chain() {
+    chain::<Udp>()
+}
+
+#[test]
+fn tcp_disconnect() {
+    disconnect::<Tcp>()
+}
+
+#[test]
+fn udp_disconnect() {
+    disconnect::<Udp>()
+}
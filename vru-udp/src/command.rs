@@ -1,57 +1,43 @@
 use std::{net::SocketAddr, io, sync::mpsc};
-use serde::{Serialize, Deserialize};
-use vru_session::handshake::{Identity, PublicKey};
+use thiserror::Error;
+use vru_session::{Event, handshake::{Identity, HandshakeError}};
+use super::linkage::FragmentError;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum Command {
-    Connect {
-        peer_pi: Identity,
-        address: SocketAddr,
-    },
-    Local {
-        destination: Identity,
-        command: LocalCommand,
-    },
-}
-
-#[derive(Debug)]
-pub enum Event {
-    Error(Error),
-    Local {
-        source: Box<PublicKey>,
-        local: LocalEvent,
-    },
-    Info(String),
-}
-
-#[derive(Debug)]
-pub enum Error {
+#[derive(Debug, Error)]
+pub enum NodeError {
+    #[error("read socket error: {}", _0)]
     ReadSocket(io::Error),
+    #[error("frame size {}, address: {}", _1, _0)]
     FrameSize(SocketAddr, usize),
-    ConnectionFailed(SocketAddr),
+    #[error("write error: {}, address: {}", _1, _0)]
     WriteTo(SocketAddr, io::Error),
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub enum LocalCommand {
-    SendText(String),
-}
-
-#[derive(Debug)]
-pub enum LocalEvent {
-    ReceivedText(String),
-    HandshakeDone { incoming: bool },
+    #[error("handshake error: {}, address: {}", _1, _0)]
+    Handshake(SocketAddr, HandshakeError),
+    #[error("mac mismatch, address: {}", _0)]
+    MacMismatch(SocketAddr),
+    #[error("fragment error: {}, address: {}", _1, _0)]
+    Fragment(SocketAddr, FragmentError),
+    #[error("unknown kind of datagram, address: {}", _0)]
+    UnknownKind(SocketAddr),
+    #[error("already connected, identity: {}", _0)]
+    AlreadyConnected(Identity),
+    #[error("no session, identity: {}", _0)]
+    NoSession(Identity),
+    #[error("too many peers, refuse incoming session, address: {}", _0)]
+    TooManyPeers(SocketAddr),
+    #[error("handshake timeout, address: {}", _0)]
+    HandshakeTimeout(SocketAddr),
 }
 
 #[derive(Clone)]
-pub struct EventSender(mpsc::Sender<Event>);
+pub struct EventSender(mpsc::Sender<Event<NodeError, SocketAddr>>);
 
 impl EventSender {
-    pub fn new(sender: mpsc::Sender<Event>) -> Self {
+    pub fn new(sender: mpsc::Sender<Event<NodeError, SocketAddr>>) -> Self {
         EventSender(sender)
     }
 
-    pub fn report(&self, event: Event) {
+    pub fn report(&self, event: Event<NodeError, SocketAddr>) {
         match self.0.send(event) {
             Ok(()) => (),
             Err(mpsc::SendError(event)) => log::warn!("failed to send event: {:?}", event),
@@ -1,34 +1,50 @@
 use std::{
     cell::RefCell,
-    collections::HashMap,
-    io,
+    io, mem,
     net::{SocketAddr, UdpSocket},
     sync::{
         mpsc, Arc, Mutex,
-        atomic::{Ordering, AtomicBool},
+        atomic::{Ordering, AtomicBool, AtomicUsize},
     },
     thread,
+    time::{Duration, Instant, SystemTime},
+};
+use vru_session::{
+    self as session,
+    Command,
+    Reply,
+    Status,
+    Event,
+    NodeDisconnected,
+    ProcessorFactory,
+    Sessions,
+    Pending,
+    handshake::{SecretKey, PublicKey, Handshake, Initiator, Responder, Payloads, xx},
 };
-use thiserror::Error;
-use vru_session::handshake::{SecretKey, PublicKey, Identity, xx};
 use super::{
-    command::{Command, Event, Error, EventSender},
-    local::{Peer, PeerHandle},
-    linkage::{Datagram, LinkToken},
+    command::{NodeError, EventSender},
+    local::{Peer, PeerHandle, Routes},
+    linkage::{Datagram, Kind, LinkToken, Reassembler, split},
 };
 
-pub struct NodeRef(mpsc::Receiver<Event>);
+// the message 0 is padded to the size of the largest message 1,
+// so the responder never sends more than it receives from a possibly spoofed address
+const PADDED_MESSAGE0: usize = xx::message_size(1, xx::DEFAULT_MAX_PAYLOAD);
+
+// the responders which are in the middle of the handshake
+const MAX_PENDING: usize = 16;
 
-#[derive(Debug, Error)]
-#[error("node disconnected")]
-pub struct NodeDisconnected;
+// finished workers are joined and expired data is dropped at most this often
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
 
-impl NodeRef {
-    pub fn recv(&self) -> Result<Event, NodeDisconnected> {
+pub struct NodeRef(mpsc::Receiver<Event<NodeError, SocketAddr>>);
+
+impl session::NodeRef<NodeError, SocketAddr> for NodeRef {
+    fn recv(&self) -> Result<Event<NodeError, SocketAddr>, NodeDisconnected> {
         self.0.recv().map_err(|mpsc::RecvError| NodeDisconnected)
     }
 
-    pub fn try_recv(&self) -> Result<Option<Event>, NodeDisconnected> {
+    fn try_recv(&self) -> Result<Option<Event<NodeError, SocketAddr>>, NodeDisconnected> {
         match self.0.try_recv() {
             Ok(event) => Ok(Some(event)),
             Err(mpsc::TryRecvError::Empty) => Ok(None),
@@ -37,38 +53,62 @@ impl NodeRef {
     }
 }
 
-pub struct Node {
+pub struct Node<P> {
+    sk: SecretKey,
+    pk: PublicKey,
+    socket: UdpSocket,
+    start_time: SystemTime,
     sender: EventSender,
-    pending_outgoing: Arc<Mutex<HashMap<SocketAddr, Identity>>>,
-    pending_handles: Arc<Mutex<HashMap<Identity, PeerHandle>>>,
-    handles: RefCell<HashMap<Identity, PeerHandle>>,
+    routes: Routes,
+    workers: Arc<Mutex<Vec<Peer>>>,
+    sessions: Sessions<PeerHandle>,
+    max_incoming: Arc<AtomicUsize>,
+    processor_factory: RefCell<P>,
+    running: Arc<AtomicBool>,
     main_thread: thread::JoinHandle<()>,
 }
 
-impl Node {
-    pub fn spawn(
+impl<P> session::Node<P> for Node<P>
+where
+    P: ProcessorFactory + Clone + Send + 'static,
+    P::Processor: Send + 'static,
+{
+    type Error = NodeError;
+    type Ref = NodeRef;
+    type Address = SocketAddr;
+
+    fn spawn(
         sk: SecretKey,
         pk: PublicKey,
-        port: u16,
+        address: Self::Address,
+        processor_factory: P,
         running: Arc<AtomicBool>,
-    ) -> io::Result<(Self, NodeRef)> {
+    ) -> Result<(Self, Self::Ref), Self::Error> {
         let (sender, rx) = mpsc::channel();
         let sender = EventSender::new(sender);
 
-        let pending_outgoing = Arc::new(Mutex::new(HashMap::new()));
-        let pending_handles = Arc::new(Mutex::new(HashMap::new()));
-        let handles = RefCell::new(HashMap::new());
+        let routes = Routes::default();
+        let workers = Arc::new(Mutex::new(Vec::new()));
+        let sessions = Sessions::default();
+        let max_incoming = Arc::new(AtomicUsize::new(usize::MAX));
+        let socket = UdpSocket::bind(address).map_err(NodeError::ReadSocket)?;
         let main_thread = {
-            let socket = UdpSocket::bind::<SocketAddr>(([0, 0, 0, 0], port).into())?;
             let listener = NodeState {
-                sk,
-                pk,
-                socket,
+                sk: sk.clone(),
+                pk: pk.clone(),
+                socket: socket.try_clone().map_err(NodeError::ReadSocket)?,
                 sender: sender.clone(),
-                pending_outgoing: pending_outgoing.clone(),
-                pending_handles: pending_handles.clone(),
-                connections: HashMap::new(),
+                routes: routes.clone(),
+                workers: workers.clone(),
+                sessions: sessions.clone(),
+                max_incoming: max_incoming.clone(),
+                reassembler: Reassembler::new(64, Duration::from_secs(10)),
+                pending: Pending::default(),
+                next_cleanup: Instant::now() + CLEANUP_INTERVAL,
+                processor_factory: processor_factory.clone(),
+                running: running.clone(),
             };
+            let running = running.clone();
             thread::Builder::new()
                 .name("node-main".to_string())
                 .spawn(move || listener.run(running))
@@ -77,63 +117,149 @@ impl Node {
 
         Ok((
             Node {
+                sk,
+                pk,
+                socket,
+                start_time: SystemTime::now(),
                 sender,
-                pending_outgoing,
-                pending_handles,
-                handles,
+                routes,
+                workers,
+                sessions,
+                max_incoming,
+                processor_factory: RefCell::new(processor_factory),
+                running,
                 main_thread,
             },
             NodeRef(rx),
         ))
     }
 
-    pub fn join(self) {
+    fn limit_incoming(&self, max: usize) {
+        self.max_incoming.store(max, Ordering::Release);
+    }
+
+    fn join(self) {
         self.main_thread.join().unwrap();
+        let workers = match Arc::try_unwrap(self.workers) {
+            Ok(workers) => workers.into_inner().unwrap(),
+            Err(workers) => workers.lock().unwrap().drain(..).collect(),
+        };
+        for peer in workers {
+            peer.join();
+        }
     }
 
-    pub fn command(&self, command: Command) {
+    fn command(&self, command: Command<Self::Address>) -> Result<Reply<Self::Address>, Self::Error> {
         match command {
             Command::Connect { address, peer_pi } => {
-                let mut h = self.pending_outgoing.lock().unwrap();
-                if h.contains_key(&address) {
-                    self.sender
-                        .report(Event::Error(Error::ConnectionFailed(address)));
-                } else {
-                    h.insert(address, peer_pi);
+                if self.sessions.contains(&peer_pi) {
+                    return Err(NodeError::AlreadyConnected(peer_pi));
                 }
+                let socket = self
+                    .socket
+                    .try_clone()
+                    .map_err(|error| NodeError::WriteTo(address, error))?;
+
+                let (sk, pk) = (self.sk.clone(), self.pk.clone());
+                let (initiator, mut message) =
+                    Initiator::new(sk, pk.clone(), &peer_pi, Payloads::default());
+                message.resize(PADDED_MESSAGE0, 0);
+
+                let link: LinkToken = rand::random();
+                let datagrams = split(&link, 0, &message)
+                    .map_err(|error| NodeError::Fragment(address, error))?
+                    .collect::<Vec<_>>();
+
+                let sessions = self.sessions.clone();
+                let sender = self.sender.clone();
+                let processor = self
+                    .processor_factory
+                    .borrow_mut()
+                    .spawn_processor(Some(peer_pi));
+                let peer = Peer::spawn(
+                    pk,
+                    socket,
+                    address,
+                    link.clone(),
+                    Handshake::Initiator(initiator),
+                    None,
+                    processor,
+                    self.routes.clone(),
+                    sessions,
+                    self.max_incoming.clone(),
+                    sender,
+                    self.running.clone(),
+                );
+
+                // the peer is registered in `routes` before the responder is able to answer
+                self.workers.lock().unwrap().push(peer);
+                for datagram in datagrams {
+                    self.socket
+                        .send_to(datagram.as_ref(), address)
+                        .map_err(|error| NodeError::WriteTo(address, error))?;
+                }
+                Ok(Reply::Done)
             },
             Command::Local {
                 destination,
                 command,
             } => {
-                if let Some(handle) = self.handles.borrow().get(&destination) {
-                    handle.send(command);
-                } else {
-                    let mut h = self.pending_handles.lock().unwrap();
-                    if let Some(handle) = h.remove(&destination) {
-                        drop(h);
-                        handle.send(command);
-                        self.handles.borrow_mut().insert(destination, handle);
-                    }
+                if !self.sessions.send(&destination, command) {
+                    return Err(NodeError::NoSession(destination));
                 }
+                Ok(Reply::Done)
+            },
+            Command::Identity => Ok(Reply::Identity(self.pk.identity())),
+            Command::ListPeers => Ok(Reply::Peers(self.sessions.info())),
+            Command::Status => Ok(Reply::Status(Status {
+                identity: self.pk.identity(),
+                address: self.socket.local_addr().map_err(NodeError::ReadSocket)?,
+                transport: "udp".to_string(),
+                peers: self.sessions.len(),
+                start_time: self.start_time,
+            })),
+            Command::AuthString { peer_pi } => match self.sessions.auth_string(&peer_pi) {
+                Some(auth_string) => Ok(Reply::AuthString(auth_string)),
+                None => Err(NodeError::NoSession(peer_pi)),
+            },
+            Command::Disconnect { peer_pi } => {
+                if !self.sessions.disconnect(&peer_pi) {
+                    return Err(NodeError::NoSession(peer_pi));
+                }
+                Ok(Reply::Done)
             },
         }
     }
 }
 
-struct NodeState {
+impl<P> Node<P> {
+    pub fn sessions(&self) -> Sessions<PeerHandle> {
+        self.sessions.clone()
+    }
+}
+
+struct NodeState<P> {
     sk: SecretKey,
     pk: PublicKey,
     socket: UdpSocket,
     sender: EventSender,
-    pending_outgoing: Arc<Mutex<HashMap<SocketAddr, Identity>>>,
-    pending_handles: Arc<Mutex<HashMap<Identity, PeerHandle>>>,
-    connections: HashMap<LinkToken, Peer>,
+    routes: Routes,
+    workers: Arc<Mutex<Vec<Peer>>>,
+    sessions: Sessions<PeerHandle>,
+    max_incoming: Arc<AtomicUsize>,
+    reassembler: Reassembler,
+    pending: Pending,
+    next_cleanup: Instant,
+    processor_factory: P,
+    running: Arc<AtomicBool>,
 }
 
-impl NodeState {
+impl<P> NodeState<P>
+where
+    P: ProcessorFactory,
+    P::Processor: Send + 'static,
+{
     fn run(mut self, running: Arc<AtomicBool>) {
-        use std::time::Duration;
         use popol::{Sources, Events, interest};
 
         let mut sources = Sources::with_capacity(1);
@@ -148,9 +274,10 @@ impl NodeState {
                         if !running.load(Ordering::Acquire) {
                             return;
                         }
+                        self.cleanup(Instant::now());
                     },
                     Err(error) if error.kind() == io::ErrorKind::Interrupted => return,
-                    Err(error) => self.sender.report(Event::Error(Error::ReadSocket(error))),
+                    Err(error) => self.sender.report(Event::Error(NodeError::ReadSocket(error))),
                 }
             }
             let mut datagram = Datagram::default();
@@ -158,53 +285,122 @@ impl NodeState {
                 Ok((length, address)) => {
                     if length != Datagram::SIZE {
                         self.sender
-                            .report(Event::Error(Error::FrameSize(address, length)));
+                            .report(Event::Error(NodeError::FrameSize(address, length)));
                     } else {
-                        self.process(address, datagram);
+                        self.process(address, Box::new(datagram));
                     }
                 },
-                Err(error) => self.sender.report(Event::Error(Error::ReadSocket(error))),
+                Err(error) => self.sender.report(Event::Error(NodeError::ReadSocket(error))),
             }
+            self.cleanup(Instant::now());
         }
+    }
 
-        for (_, ctx) in self.connections {
-            ctx.join();
+    fn cleanup(&mut self, now: Instant) {
+        if now < self.next_cleanup {
+            return;
+        }
+        self.next_cleanup = now + CLEANUP_INTERVAL;
+        self.reassembler.expire(now);
+        self.routes.expire(now);
+        // the worker removes its route itself when it stops
+        let mut workers = self.workers.lock().unwrap();
+        let (finished, alive) = mem::take(&mut *workers)
+            .into_iter()
+            .partition::<Vec<_>, _>(Peer::is_finished);
+        *workers = alive;
+        drop(workers);
+        for peer in finished {
+            peer.join();
         }
     }
 
-    fn process(&mut self, address: SocketAddr, datagram: Datagram) {
-        let link_token = datagram.link();
-        if let Some(ctx) = self.connections.get(&link_token) {
-            ctx.send(address, datagram);
-        } else {
-            let mut h = self.pending_outgoing.lock().unwrap();
-            if let Some(peer_pi) = h.remove(&address) {
-                drop(h);
-
-                use rac::{Array, LineValid};
-                let mut seed = Array::default();
-                rand::Rng::fill(&mut rand::thread_rng(), seed.as_mut());
-                let mut datagram = Datagram::default();
-                let (state, message) = xx::out0(&seed, &peer_pi);
-                datagram.as_mut()[..1120].clone_from_slice(&message.0.clone_line());
-                rand::Rng::fill(&mut rand::thread_rng(), datagram.as_mut()[1120..].as_mut());
-                if let Err(error) = self.socket.send_to(datagram.as_ref(), address) {
-                    self.sender
-                        .report(Event::Error(Error::WriteTo(address, error)));
-                }
+    fn process(&mut self, address: SocketAddr, datagram: Box<Datagram>) {
+        match datagram.kind() {
+            Some(Kind::Handshake) => match self.reassembler.insert(&datagram) {
+                Ok(Some((number, message))) => {
+                    self.handshake(address, datagram.link(), number, message)
+                },
+                Ok(None) => (),
+                Err(error) => self
+                    .sender
+                    .report(Event::Error(NodeError::Fragment(address, error))),
+            },
+            Some(Kind::Data) => self.routes.data(address, datagram),
+            None => self
+                .sender
+                .report(Event::Error(NodeError::UnknownKind(address))),
+        }
+    }
 
-                let (sk, pk) = (self.sk.clone(), self.pk.clone());
-                let sender = self.sender.clone();
-                let (peer, peer_handle) = Peer::spawn(sk, pk, Some(state), sender);
-                peer.send(address, datagram);
-                // TODO:
-                self.connections.insert(rand::random(), peer);
-                let mut h = self.pending_handles.lock().unwrap();
-                h.insert(peer_pi, peer_handle);
-            } else {
+    fn handshake(
+        &mut self,
+        address: SocketAddr,
+        link_token: LinkToken,
+        number: u8,
+        message: Vec<u8>,
+    ) {
+        // the worker registers the token of the session when the handshake is done
+        let message = match self.routes.handshake(&link_token, address, number, message) {
+            Ok(()) => return,
+            Err(message) => message,
+        };
+
+        if number == 0 {
+            if message.len() < PADDED_MESSAGE0 {
+                log::debug!("drop message 0 from {}, it is not padded", address);
+                return;
+            }
+            let mut message = message;
+            message.truncate(xx::message_size(0, 0));
+            if self.sessions.len() >= self.max_incoming.load(Ordering::Acquire) {
                 self.sender
-                    .report(Event::Info(format!("incoming from: {}", address)));
+                    .report(Event::Error(NodeError::TooManyPeers(address)));
+                return;
             }
+            let pending = match self.pending.acquire(MAX_PENDING) {
+                Some(v) => v,
+                None => {
+                    log::debug!("drop message 0 from {}, too many pending handshakes", address);
+                    return;
+                },
+            };
+            let socket = match self.socket.try_clone() {
+                Ok(v) => v,
+                Err(error) => {
+                    self.sender
+                        .report(Event::Error(NodeError::WriteTo(address, error)));
+                    return;
+                },
+            };
+            self.sender
+                .report(Event::DebugInfo(format!("incoming from: {}", address)));
+            let peer = Peer::spawn(
+                self.pk.clone(),
+                socket,
+                address,
+                link_token.clone(),
+                Handshake::Responder(Responder::new(
+                    self.sk.clone(),
+                    self.pk.clone(),
+                    Payloads::default(),
+                )),
+                Some(pending),
+                self.processor_factory.spawn_processor(None),
+                self.routes.clone(),
+                self.sessions.clone(),
+                self.max_incoming.clone(),
+                self.sender.clone(),
+                self.running.clone(),
+            );
+            let _ = self.routes.handshake(&link_token, address, number, message);
+            self.workers.lock().unwrap().push(peer);
+        } else {
+            log::debug!(
+                "drop handshake message {} from {}, unknown link",
+                number,
+                address
+            );
         }
     }
 }
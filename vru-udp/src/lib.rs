@@ -2,11 +2,12 @@
 #![allow(clippy::type_complexity)]
 
 mod command;
-pub use self::command::{Command, LocalCommand, Event, Error, LocalEvent};
+pub use self::command::NodeError;
 
 mod global;
-pub use self::global::{Node, NodeRef, NodeDisconnected};
+pub use self::global::{Node, NodeRef};
 
 mod local;
+pub use self::local::PeerHandle;
 
 mod linkage;
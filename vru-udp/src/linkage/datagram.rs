@@ -23,25 +23,88 @@ impl AsMut<[u8]> for Datagram {
     }
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Kind {
+    Handshake,
+    Data,
+}
+
 impl Datagram {
     pub const SIZE: usize = 1280;
 
+    // link token and kind
+    pub const HEADER_SIZE: usize = LinkToken::SIZE + 1;
+
+    pub const PAYLOAD_SIZE: usize = Self::SIZE - Self::HEADER_SIZE;
+
+    // the payload is random until it is written
+    pub fn new(link: &LinkToken, kind: Kind) -> Self {
+        let mut datagram = Datagram::default();
+        datagram.0[..LinkToken::SIZE].clone_from_slice(&link.0);
+        datagram.0[LinkToken::SIZE] = match kind {
+            Kind::Handshake => 0,
+            Kind::Data => 1,
+        };
+        rand::thread_rng().fill(datagram.payload_mut());
+        datagram
+    }
+
     pub fn link(&self) -> LinkToken {
-        let mut token = LinkToken([0; 16]);
-        token.0.clone_from_slice(&self.0[..16]);
+        let mut token = LinkToken([0; LinkToken::SIZE]);
+        token.0.clone_from_slice(&self.0[..LinkToken::SIZE]);
         token
     }
+
+    pub fn kind(&self) -> Option<Kind> {
+        match self.0[LinkToken::SIZE] {
+            0 => Some(Kind::Handshake),
+            1 => Some(Kind::Data),
+            _ => None,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.0[Self::HEADER_SIZE..]
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.0[Self::HEADER_SIZE..]
+    }
 }
 
-#[derive(Hash, Eq, PartialEq)]
-pub struct LinkToken([u8; 16]);
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct LinkToken([u8; Self::SIZE]);
+
+impl LinkToken {
+    pub const SIZE: usize = 16;
+
+    // both parties derive the same pair from the handshake hash,
+    // the first token is for the initiator's datagrams, the second is for the responder's
+    pub fn pair(hash: &[u8]) -> (Self, Self) {
+        use sha3::{Sha3_256, Digest};
+
+        let token = |label: &[u8]| {
+            let digest = Sha3_256::default().chain(label).chain(hash).finalize();
+            let mut token = LinkToken([0; LinkToken::SIZE]);
+            token.0.clone_from_slice(&digest[..LinkToken::SIZE]);
+            token
+        };
+        (token(b"vru initiator link"), token(b"vru responder link"))
+    }
+}
+
+impl AsRef<[u8]> for LinkToken {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
 
 impl Distribution<LinkToken> for Standard {
     fn sample<R>(&self, rng: &mut R) -> LinkToken
     where
         R: Rng + ?Sized,
     {
-        let mut token = LinkToken([0; 16]);
+        let mut token = LinkToken([0; LinkToken::SIZE]);
         rng.fill(token.0.as_mut());
         token
     }
@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use vru_session::handshake::xx;
+use super::datagram::{Datagram, Kind, LinkToken};
+
+// payload of a handshake datagram:
+// number of the message (1 byte), index of the fragment (1 byte),
+// count of fragments (1 byte), length of the fragment (2 bytes), the fragment
+const HEADER_SIZE: usize = 5;
+
+pub const FRAGMENT_SIZE: usize = Datagram::PAYLOAD_SIZE - HEADER_SIZE;
+
+// the message 2 is the largest, it carries two payloads,
+// the transport accepts payloads of at most `DEFAULT_MAX_PAYLOAD` bytes
+const MAX_MESSAGE_SIZE: usize = xx::message_size(2, xx::DEFAULT_MAX_PAYLOAD);
+
+pub const MAX_FRAGMENTS: usize = MAX_MESSAGE_SIZE.div_ceil(FRAGMENT_SIZE);
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum FragmentError {
+    #[error("message size {}", _0)]
+    MessageSize(usize),
+    #[error("fragment size {}", _0)]
+    FragmentSize(usize),
+    #[error("fragments count {}", _0)]
+    Count(u8),
+    #[error("fragment index {}, count: {}", index, count)]
+    Index { index: u8, count: u8 },
+    #[error("fragments count {}, expected: {}", count, expected)]
+    Inconsistent { count: u8, expected: u8 },
+}
+
+pub fn split<'a>(
+    link: &'a LinkToken,
+    number: u8,
+    message: &'a [u8],
+) -> Result<impl Iterator<Item = Datagram> + 'a, FragmentError> {
+    let count = message.len().div_ceil(FRAGMENT_SIZE);
+    if count == 0 || count > MAX_FRAGMENTS {
+        return Err(FragmentError::MessageSize(message.len()));
+    }
+
+    Ok(message
+        .chunks(FRAGMENT_SIZE)
+        .enumerate()
+        .map(move |(index, chunk)| {
+            let mut datagram = Datagram::new(link, Kind::Handshake);
+            let payload = datagram.payload_mut();
+            payload[0] = number;
+            payload[1] = index as u8;
+            payload[2] = count as u8;
+            payload[3..HEADER_SIZE].clone_from_slice(&(chunk.len() as u16).to_le_bytes());
+            payload[HEADER_SIZE..(HEADER_SIZE + chunk.len())].clone_from_slice(chunk);
+            datagram
+        }))
+}
+
+struct Partial {
+    created: Instant,
+    fragments: Vec<Option<Vec<u8>>>,
+}
+
+impl Partial {
+    fn complete(&self) -> bool {
+        self.fragments.iter().all(Option::is_some)
+    }
+}
+
+// collects fragments of handshake messages, keeps at most `capacity`
+// incomplete messages, drops those which are incomplete for longer than `timeout`
+pub struct Reassembler {
+    capacity: usize,
+    timeout: Duration,
+    partials: HashMap<(LinkToken, u8), Partial>,
+}
+
+impl Reassembler {
+    pub fn new(capacity: usize, timeout: Duration) -> Self {
+        Reassembler {
+            capacity,
+            timeout,
+            partials: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, datagram: &Datagram) -> Result<Option<(u8, Vec<u8>)>, FragmentError> {
+        let now = Instant::now();
+        self.expire(now);
+
+        let payload = datagram.payload();
+        let (number, index, count) = (payload[0], payload[1], payload[2]);
+        let mut length = [0; 2];
+        length.clone_from_slice(&payload[3..HEADER_SIZE]);
+        let length = u16::from_le_bytes(length) as usize;
+        if length == 0 || length > FRAGMENT_SIZE {
+            return Err(FragmentError::FragmentSize(length));
+        }
+        if count == 0 || count as usize > MAX_FRAGMENTS {
+            return Err(FragmentError::Count(count));
+        }
+        if index >= count {
+            return Err(FragmentError::Index { index, count });
+        }
+        let fragment = &payload[HEADER_SIZE..(HEADER_SIZE + length)];
+
+        let key = (datagram.link(), number);
+        if !self.partials.contains_key(&key) && self.partials.len() >= self.capacity {
+            // evict the oldest incomplete message
+            let oldest = self
+                .partials
+                .iter()
+                .min_by_key(|(_, partial)| partial.created)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                log::debug!("drop incomplete message {:?}", oldest);
+                self.partials.remove(&oldest);
+            }
+        }
+        let partial = self.partials.entry(key.clone()).or_insert_with(|| Partial {
+            created: now,
+            fragments: vec![None; count as usize],
+        });
+        if partial.fragments.len() != count as usize {
+            return Err(FragmentError::Inconsistent {
+                count,
+                expected: partial.fragments.len() as u8,
+            });
+        }
+        let slot = &mut partial.fragments[index as usize];
+        if slot.is_none() {
+            *slot = Some(fragment.to_vec());
+        }
+
+        if partial.complete() {
+            let partial = self.partials.remove(&key).expect("just inserted");
+            let message = partial.fragments.into_iter().flatten().flatten().collect();
+            Ok(Some((number, message)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.partials.retain(|key, partial| {
+            let alive = now.duration_since(partial.created) < timeout;
+            if !alive {
+                log::debug!("drop incomplete message {:?} by timeout", key);
+            }
+            alive
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use rac::{Array, generic_array::sequence::GenericSequence};
+    use vru_session::handshake::{PublicKey, Initiator, Responder, Payloads, Step, Next, xx};
+    use super::{split, Reassembler, FragmentError, FRAGMENT_SIZE, MAX_FRAGMENTS};
+    use crate::linkage::LinkToken;
+
+    fn message(length: usize) -> Vec<u8> {
+        (0..length).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let link = rand::random::<LinkToken>();
+        let message = message(3472);
+        let mut datagrams = split(&link, 2, &message).unwrap().collect::<Vec<_>>();
+        assert_eq!(datagrams.len(), 3);
+        datagrams.reverse();
+
+        let mut reassembler = Reassembler::new(4, Duration::from_secs(10));
+        assert_eq!(reassembler.insert(&datagrams[0]), Ok(None));
+        assert_eq!(reassembler.insert(&datagrams[0]), Ok(None));
+        assert_eq!(reassembler.insert(&datagrams[1]), Ok(None));
+        assert_eq!(reassembler.insert(&datagrams[2]), Ok(Some((2, message))));
+    }
+
+    // the message 2 with both payloads of the maximal length
+    #[test]
+    fn largest_message() {
+        let (i_pk, i_sk) = PublicKey::gen(&Array::generate(|i| i as u8));
+        let (r_pk, r_sk) = PublicKey::gen(&Array::generate(|i| !i as u8));
+        let max = xx::DEFAULT_MAX_PAYLOAD;
+        let payloads = Payloads::new(vec![0x13; max], vec![0x23; max], max).unwrap();
+        let (initiator, message) = Initiator::new(i_sk, i_pk, &r_pk.identity(), payloads);
+        let responder = Responder::new(r_sk, r_pk, Payloads::default());
+        let Step { message: reply, next } = responder.step(&message).unwrap();
+        let (number, message) = initiator.step(&reply.unwrap().1).unwrap().message.unwrap();
+        assert_eq!(message.len(), xx::message_size(2, max));
+
+        let link = rand::random::<LinkToken>();
+        let datagrams = split(&link, number, &message).unwrap().collect::<Vec<_>>();
+        assert_eq!(datagrams.len(), MAX_FRAGMENTS);
+        let mut reassembler = Reassembler::new(4, Duration::from_secs(10));
+        let (last, rest) = datagrams.split_last().unwrap();
+        for datagram in rest {
+            assert_eq!(reassembler.insert(datagram), Ok(None));
+        }
+        let (_, message) = reassembler.insert(last).unwrap().unwrap();
+        match next {
+            Next::Handshake(responder) => assert!(matches!(
+                responder.step(&message).unwrap().next,
+                Next::Established(_),
+            )),
+            Next::Established(_) => panic!("the responder is established too early"),
+        }
+    }
+
+    #[test]
+    fn drop_incomplete() {
+        let link = rand::random::<LinkToken>();
+        let message = message(FRAGMENT_SIZE + 1);
+        let datagrams = split(&link, 1, &message).unwrap().collect::<Vec<_>>();
+
+        let mut reassembler = Reassembler::new(4, Duration::from_secs(10));
+        assert_eq!(reassembler.insert(&datagrams[0]), Ok(None));
+        reassembler.expire(Instant::now() + Duration::from_secs(11));
+        assert_eq!(reassembler.insert(&datagrams[1]), Ok(None));
+        assert_eq!(reassembler.insert(&datagrams[0]), Ok(Some((1, message))));
+    }
+
+    #[test]
+    fn bounded() {
+        let mut reassembler = Reassembler::new(1, Duration::from_secs(10));
+        let message = message(FRAGMENT_SIZE + 1);
+        let first = rand::random::<LinkToken>();
+        let first = split(&first, 1, &message).unwrap().collect::<Vec<_>>();
+        let second = rand::random::<LinkToken>();
+        let second = split(&second, 1, &message).unwrap().collect::<Vec<_>>();
+
+        assert_eq!(reassembler.insert(&first[0]), Ok(None));
+        assert_eq!(reassembler.insert(&second[0]), Ok(None));
+        assert_eq!(reassembler.insert(&first[1]), Ok(None));
+        assert_eq!(reassembler.insert(&second[1]), Ok(None));
+    }
+
+    #[test]
+    fn malformed() {
+        let link = rand::random::<LinkToken>();
+        let mut datagram = split(&link, 0, &message(16)).unwrap().next().unwrap();
+        datagram.payload_mut()[1] = 1;
+
+        let mut reassembler = Reassembler::new(4, Duration::from_secs(10));
+        let error = FragmentError::Index { index: 1, count: 1 };
+        assert_eq!(reassembler.insert(&datagram), Err(error));
+        assert!(split(&link, 0, &message(0)).is_err());
+    }
+}
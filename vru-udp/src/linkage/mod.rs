@@ -1,2 +1,8 @@
 mod datagram;
-pub use self::datagram::{Datagram, LinkToken};
+pub use self::datagram::{Datagram, Kind, LinkToken};
+
+mod fragment;
+pub use self::fragment::{split, Reassembler, FragmentError};
+
+mod replay;
+pub use self::replay::ReplayWindow;
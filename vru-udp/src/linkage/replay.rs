@@ -0,0 +1,77 @@
+// accepts each nonce at most once, the nonces might come out of order,
+// but those older than the window behind the highest accepted nonce are rejected
+#[derive(Default)]
+pub struct ReplayWindow {
+    // the highest accepted nonce plus one, zero if nothing is accepted yet
+    next: u64,
+    // the bit `i` is set if the nonce `next - 1 - i` is accepted
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    pub const SIZE: u64 = 64;
+
+    // the caller checks the nonce before decryption and accepts it after
+    pub fn check(&self, nonce: u64) -> bool {
+        if nonce >= self.next {
+            return true;
+        }
+        let behind = self.next - 1 - nonce;
+        behind < Self::SIZE && self.bitmap & (1 << behind) == 0
+    }
+
+    pub fn accept(&mut self, nonce: u64) {
+        if nonce >= self.next {
+            let shift = nonce - self.next + 1;
+            self.bitmap = if shift >= Self::SIZE {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.next = nonce + 1;
+        } else {
+            self.bitmap |= 1 << (self.next - 1 - nonce);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplayWindow;
+
+    fn accept(window: &mut ReplayWindow, nonce: u64) -> bool {
+        let fresh = window.check(nonce);
+        if fresh {
+            window.accept(nonce);
+        }
+        fresh
+    }
+
+    #[test]
+    fn reordered_and_lost() {
+        let mut window = ReplayWindow::default();
+        assert!(accept(&mut window, 0));
+        assert!(accept(&mut window, 2));
+        assert!(accept(&mut window, 1));
+        assert!(!accept(&mut window, 1));
+        assert!(!accept(&mut window, 2));
+        // the nonce 3 is lost
+        assert!(accept(&mut window, 4));
+        assert!(accept(&mut window, 3));
+        assert!(!accept(&mut window, 0));
+    }
+
+    #[test]
+    fn too_old() {
+        let mut window = ReplayWindow::default();
+        assert!(accept(&mut window, 5));
+        assert!(accept(&mut window, 5 + ReplayWindow::SIZE));
+        assert!(!accept(&mut window, 5));
+        assert!(!accept(&mut window, 4));
+        assert!(accept(&mut window, 6));
+        assert!(accept(&mut window, 1000));
+        assert!(!accept(&mut window, 6 + ReplayWindow::SIZE));
+        assert!(accept(&mut window, 1000 - ReplayWindow::SIZE + 1));
+    }
+}
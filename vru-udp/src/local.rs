@@ -1,91 +1,536 @@
-use std::{net::SocketAddr, sync::mpsc, thread};
-use vru_session::handshake::{PublicKey, SecretKey, xx};
+use std::{
+    collections::{HashMap, VecDeque},
+    mem,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        mpsc, Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use rac::Array;
+use vru_session::{
+    Event, Processor, Outgoing, PeerDisconnected, PeerInfo, SessionHandle, Sessions, PendingSlot,
+    handshake::{PublicKey, Identity, ShortAuthString, TrivialCipher, Handshake, Step, Next, Established},
+};
 use super::{
-    command::{Event, LocalCommand, EventSender},
-    linkage::Datagram,
+    command::{NodeError, EventSender},
+    linkage::{Datagram, Kind, LinkToken, ReplayWindow, split},
 };
 
+// length (2 bytes), nonce (8 bytes) and tag (16 bytes) precede the encrypted data,
+// the nonce is explicit, so a lost or reordered datagram does not break the session
+const DATA_OFFSET: usize = 26;
+
+const TAG_OFFSET: usize = 10;
+
+// the worker stops if the handshake is not done in time
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 enum PeerMessage {
+    Handshake {
+        address: SocketAddr,
+        number: u8,
+        message: Vec<u8>,
+    },
     Network {
         address: SocketAddr,
-        datagram: Datagram,
+        datagram: Box<Datagram>,
     },
-    Command(LocalCommand),
+    Outgoing(Vec<u8>),
+    Close,
+}
+
+// routes datagrams to the workers by the link token; the table is shared with the workers,
+// so the worker registers the new token itself when the handshake is done
+// and the receiving loop never waits for it
+#[derive(Clone, Default)]
+pub struct Routes(Arc<Mutex<RoutesInner>>);
+
+#[derive(Default)]
+struct RoutesInner {
+    senders: HashMap<LinkToken, mpsc::Sender<PeerMessage>>,
+    // data datagrams which came before the worker registered the token
+    early: VecDeque<(Instant, SocketAddr, Box<Datagram>)>,
+}
+
+impl Routes {
+    const EARLY_CAPACITY: usize = 64;
+
+    const EARLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+    // gives the message back if there is no worker for the link
+    pub fn handshake(
+        &self,
+        link: &LinkToken,
+        address: SocketAddr,
+        number: u8,
+        message: Vec<u8>,
+    ) -> Result<(), Vec<u8>> {
+        let inner = self.0.lock().unwrap();
+        let sender = match inner.senders.get(link) {
+            Some(sender) => sender,
+            None => return Err(message),
+        };
+        let message = PeerMessage::Handshake {
+            address,
+            number,
+            message,
+        };
+        if sender.send(message).is_err() {
+            log::debug!("drop handshake message from {}, the worker is stopped", address);
+        }
+        Ok(())
+    }
+
+    // keeps the datagram for a while if there is no worker for the link yet
+    pub fn data(&self, address: SocketAddr, datagram: Box<Datagram>) {
+        let now = Instant::now();
+        let mut inner = self.0.lock().unwrap();
+        inner.expire(now);
+        match inner.senders.get(&datagram.link()) {
+            Some(sender) => {
+                if sender.send(PeerMessage::Network { address, datagram }).is_err() {
+                    log::debug!("drop datagram from {}, the worker is stopped", address);
+                }
+            },
+            None => {
+                if inner.early.len() >= Self::EARLY_CAPACITY {
+                    inner.early.pop_front();
+                    log::debug!("drop the oldest datagram of unknown link");
+                }
+                inner.early.push_back((now, address, datagram));
+            },
+        }
+    }
+
+    pub fn expire(&self, now: Instant) {
+        self.0.lock().unwrap().expire(now);
+    }
+
+    fn insert(&self, link: LinkToken, sender: mpsc::Sender<PeerMessage>) {
+        self.0.lock().unwrap().senders.insert(link, sender);
+    }
+
+    fn remove(&self, link: &LinkToken) {
+        self.0.lock().unwrap().senders.remove(link);
+    }
+
+    // replaces the token of the handshake with the token of the session,
+    // passes the datagrams which came early to the worker
+    fn relink(&self, old: &LinkToken, link: LinkToken, sender: &mpsc::Sender<PeerMessage>) {
+        let mut inner = self.0.lock().unwrap();
+        inner.senders.remove(old);
+        let early = mem::take(&mut inner.early);
+        for (received, address, datagram) in early {
+            if datagram.link() == link {
+                let _ = sender.send(PeerMessage::Network { address, datagram });
+            } else {
+                inner.early.push_back((received, address, datagram));
+            }
+        }
+        inner.senders.insert(link, sender.clone());
+    }
+}
+
+impl RoutesInner {
+    fn expire(&mut self, now: Instant) {
+        while let Some((received, ..)) = self.early.front() {
+            if now.duration_since(*received) < Routes::EARLY_TIMEOUT {
+                break;
+            }
+            self.early.pop_front();
+            log::debug!("drop datagram, unknown link");
+        }
+    }
 }
 
 pub struct Peer {
     worker_thread: thread::JoinHandle<()>,
-    sender: mpsc::Sender<PeerMessage>,
 }
 
 impl Peer {
-    pub fn send(&self, address: SocketAddr, datagram: Datagram) {
-        self.sender
-            .send(PeerMessage::Network { address, datagram })
-            .unwrap()
+    pub fn is_finished(&self) -> bool {
+        self.worker_thread.is_finished()
     }
 
     pub fn join(self) {
         self.worker_thread.join().unwrap();
     }
 
-    pub fn spawn(
-        sk: SecretKey,
+    // the worker is reachable by `link` in `routes` until the handshake is done,
+    // the handle is registered in `sessions` when the handshake is done,
+    // both are removed when the worker stops
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn<P>(
         pk: PublicKey,
-        handshake_state: Option<xx::InitiatorsEphemeral>,
+        socket: UdpSocket,
+        address: SocketAddr,
+        link: LinkToken,
+        handshake: Handshake,
+        pending: Option<PendingSlot>,
+        processor: P,
+        routes: Routes,
+        sessions: Sessions<PeerHandle>,
+        max_incoming: Arc<AtomicUsize>,
         event_sender: EventSender,
-    ) -> (Self, PeerHandle) {
+        running: Arc<AtomicBool>,
+    ) -> Self
+    where
+        P: Processor + Send + 'static,
+    {
         let (sender, receiver) = mpsc::channel();
+        routes.insert(link.clone(), sender.clone());
+        let route = link.clone();
 
         let state = PeerState {
-            sk,
             pk,
-            handshake_state,
+            socket,
+            address,
+            link,
+            route,
+            state: Some(State::Handshake(Box::new(handshake))),
+            deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+            pending,
+            processor,
+            sender,
+            routes,
+            handle: None,
+            sessions,
+            max_incoming,
+            send_nonce: 0,
+            replay: ReplayWindow::default(),
             receiver,
             event_sender,
         };
         let worker_thread = thread::Builder::new()
             .name("node-worker".to_string())
-            .spawn(move || state.run())
+            .spawn(move || state.run(running))
             .expect("failed to spawn thread");
 
-        (
-            Peer {
-                worker_thread,
-                sender: sender.clone(),
-            },
-            PeerHandle { sender },
-        )
+        Peer { worker_thread }
     }
 }
 
+// the handle of the established session
+#[derive(Clone)]
 pub struct PeerHandle {
     sender: mpsc::Sender<PeerMessage>,
+    stats: Arc<Stats>,
 }
 
-impl PeerHandle {
-    pub fn send(&self, command: LocalCommand) {
-        self.sender.send(PeerMessage::Command(command)).unwrap()
+struct Stats {
+    address: Mutex<SocketAddr>,
+    incoming: bool,
+    handshake_time: SystemTime,
+    auth_string: ShortAuthString,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl SessionHandle for PeerHandle {
+    type Address = SocketAddr;
+
+    fn send(&self, data: Vec<u8>) -> Result<(), PeerDisconnected> {
+        self.sender
+            .send(PeerMessage::Outgoing(data))
+            .map_err(|_| PeerDisconnected)
+    }
+
+    // there is no message to notify the remote peer, it just stops receiving answers
+    fn close(&self) -> Result<(), PeerDisconnected> {
+        self.sender
+            .send(PeerMessage::Close)
+            .map_err(|_| PeerDisconnected)
+    }
+
+    fn info(&self, identity: &Identity) -> PeerInfo<SocketAddr> {
+        PeerInfo {
+            identity: identity.clone(),
+            address: *self.stats.address.lock().unwrap(),
+            transport: "udp".to_string(),
+            incoming: self.stats.incoming,
+            handshake_time: self.stats.handshake_time,
+            bytes_in: self.stats.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.stats.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+
+    fn auth_string(&self) -> ShortAuthString {
+        self.stats.auth_string
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.stats, &other.stats)
     }
 }
 
-struct PeerState {
-    sk: SecretKey,
+enum State {
+    Handshake(Box<Handshake>),
+    Done(TrivialCipher, Box<PublicKey>),
+}
+
+struct PeerState<P> {
     pk: PublicKey,
-    // TODO:
-    handshake_state: Option<xx::InitiatorsEphemeral>,
+    socket: UdpSocket,
+    address: SocketAddr,
+    link: LinkToken,
+    // the token of incoming datagrams
+    route: LinkToken,
+    state: Option<State>,
+    deadline: Instant,
+    pending: Option<PendingSlot>,
+    processor: P,
+    sender: mpsc::Sender<PeerMessage>,
+    routes: Routes,
+    handle: Option<PeerHandle>,
+    sessions: Sessions<PeerHandle>,
+    max_incoming: Arc<AtomicUsize>,
+    send_nonce: u64,
+    replay: ReplayWindow,
     receiver: mpsc::Receiver<PeerMessage>,
     event_sender: EventSender,
 }
 
-impl PeerState {
-    fn run(self) {
-        // TODO:
-        let _ = (&self.sk, &self.pk, &self.handshake_state);
-        while let Ok(PeerMessage::Network { address, datagram }) = self.receiver.recv() {
-            let _ = datagram;
+impl<P> PeerState<P>
+where
+    P: Processor,
+{
+    fn run(mut self, running: Arc<AtomicBool>) {
+        while running.load(Ordering::Acquire) {
+            if matches!(self.state, Some(State::Handshake(_))) && Instant::now() > self.deadline {
+                let error = NodeError::HandshakeTimeout(self.address);
+                self.event_sender.report(Event::Error(error));
+                break;
+            }
+            let message = match self.receiver.recv_timeout(Duration::from_secs(1)) {
+                Ok(message) => message,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            match message {
+                PeerMessage::Handshake {
+                    address,
+                    number,
+                    message,
+                } => {
+                    if let Some(state) = self.state.take() {
+                        match self.take(state, address, number, message) {
+                            Ok(state) => self.state = Some(state),
+                            Err(error) => {
+                                self.event_sender.report(Event::Error(error));
+                                break;
+                            },
+                        }
+                    }
+                },
+                // the link token is not secret, follow the address only if the datagram is authentic
+                PeerMessage::Network { address, datagram } => {
+                    if self.receive(datagram) {
+                        self.set_address(address);
+                    }
+                },
+                PeerMessage::Outgoing(data) => self.send_data(data),
+                PeerMessage::Close => {
+                    log::info!("close session with {}", self.address);
+                    break;
+                },
+            }
+        }
+
+        self.routes.remove(&self.route);
+        if let (Some(State::Done(_, peer_pk)), Some(handle)) = (&self.state, &self.handle)
+        {
+            let peer = peer_pk.identity();
+            self.sessions.remove(&peer, handle);
+            self.processor.disconnected();
+            self.event_sender.report(Event::Disconnected { peer });
+        }
+    }
+
+    fn take(
+        &mut self,
+        state: State,
+        address: SocketAddr,
+        number: u8,
+        message: Vec<u8>,
+    ) -> Result<State, NodeError> {
+        let handshake = match state {
+            State::Handshake(handshake) if handshake.expect_next() == number => handshake,
+            state => {
+                log::warn!("unexpected handshake message {} from {}", number, address);
+                return Ok(state);
+            },
+        };
+        let initiator = handshake.is_initiator();
+        let Step { message, next } = handshake
+            .step(&message)
+            .map_err(|error| NodeError::Handshake(address, error))?;
+        self.address = address;
+        if let Some((number, message)) = message {
+            self.send_message(number, &message);
+        }
+        match next {
+            Next::Handshake(handshake) => Ok(State::Handshake(handshake)),
+            Next::Established(established) => {
+                let Established { cipher, hash, peer, .. } = *established;
+                self.relink(&hash, initiator);
+                let peer = Box::new(peer);
+                self.done(&peer, &hash, !initiator)?;
+                Ok(State::Done(cipher, peer))
+            },
+        }
+    }
+
+    // the handshake token is not used anymore, each direction has its own token
+    fn relink(&mut self, hash: &[u8], initiator: bool) {
+        let (initiators, responders) = LinkToken::pair(hash);
+        let (send, receive) = if initiator {
+            (initiators, responders)
+        } else {
+            (responders, initiators)
+        };
+        self.link = send;
+        self.routes.relink(&self.route, receive.clone(), &self.sender);
+        self.route = receive;
+    }
+
+    fn done(&mut self, peer_pk: &PublicKey, hash: &[u8], incoming: bool) -> Result<(), NodeError> {
+        self.pending = None;
+        let handle = PeerHandle {
+            sender: self.sender.clone(),
+            stats: Arc::new(Stats {
+                address: Mutex::new(self.address),
+                incoming,
+                handshake_time: SystemTime::now(),
+                auth_string: ShortAuthString::new(hash, &self.pk.identity(), &peer_pk.identity()),
+                bytes_in: AtomicU64::new(0),
+                bytes_out: AtomicU64::new(0),
+            }),
+        };
+        let limit = if incoming {
+            self.max_incoming.load(Ordering::Acquire)
+        } else {
+            usize::MAX
+        };
+        if !self.sessions.insert(peer_pk.identity(), handle.clone(), limit) {
+            return Err(NodeError::TooManyPeers(self.address));
+        }
+        let sender = handle.clone();
+        let outgoing = Outgoing::new(move |data| sender.send(data));
+        self.handle = Some(handle);
+        self.processor.handshake_done(peer_pk, hash, outgoing);
+        log::info!("handshake done with {}, identity: {}", self.address, peer_pk.identity());
+        self.event_sender.report(Event::HandshakeDone {
+            peer: Box::new(peer_pk.clone()),
+            address: self.address,
+            incoming,
+        });
+        Ok(())
+    }
+
+    fn send_data(&mut self, data: Vec<u8>) {
+        let cipher = match &mut self.state {
+            Some(State::Done(cipher, _)) => cipher,
+            _ => {
+                log::warn!("handshake is not done, drop command");
+                return;
+            },
+        };
+        let mut data = data;
+        if DATA_OFFSET + data.len() > Datagram::PAYLOAD_SIZE {
+            self.event_sender
+                .report(Event::Error(NodeError::FrameSize(self.address, data.len())));
+            return;
+        }
+        if let Some(handle) = &self.handle {
+            handle.stats.bytes_out.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        let nonce = self.send_nonce;
+        self.send_nonce += 1;
+        let tag = cipher.send.encrypt_at(nonce, self.link.as_ref(), data.as_mut());
+        let mut datagram = Datagram::new(&self.link, Kind::Data);
+        let payload = datagram.payload_mut();
+        payload[..2].clone_from_slice(&(data.len() as u16).to_le_bytes());
+        payload[2..TAG_OFFSET].clone_from_slice(&nonce.to_le_bytes());
+        payload[TAG_OFFSET..DATA_OFFSET].clone_from_slice(tag.as_ref());
+        payload[DATA_OFFSET..(DATA_OFFSET + data.len())].clone_from_slice(&data);
+        self.send_datagram(datagram);
+    }
+
+    // returns true if the datagram is authentic and fresh
+    fn receive(&mut self, datagram: Box<Datagram>) -> bool {
+        let (cipher, peer_pk) = match &mut self.state {
+            Some(State::Done(cipher, peer_pk)) => (cipher, peer_pk),
+            _ => {
+                log::warn!("handshake is not done, drop datagram");
+                return false;
+            },
+        };
+        let payload = datagram.payload();
+        let mut length = [0; 2];
+        length.clone_from_slice(&payload[..2]);
+        let length = u16::from_le_bytes(length) as usize;
+        if DATA_OFFSET + length > Datagram::PAYLOAD_SIZE {
+            self.event_sender
+                .report(Event::Error(NodeError::FrameSize(self.address, length)));
+            return false;
+        }
+        let mut nonce = [0; 8];
+        nonce.clone_from_slice(&payload[2..TAG_OFFSET]);
+        let nonce = u64::from_le_bytes(nonce);
+        if !self.replay.check(nonce) {
+            log::debug!("drop replayed or too old datagram from {}, nonce: {}", self.address, nonce);
+            return false;
+        }
+        let mut tag = Array::default();
+        tag.clone_from_slice(&payload[TAG_OFFSET..DATA_OFFSET]);
+        let mut data = payload[DATA_OFFSET..(DATA_OFFSET + length)].to_vec();
+        match cipher.receive.decrypt_at(nonce, datagram.link().as_ref(), data.as_mut(), &tag) {
+            Ok(()) => {
+                self.replay.accept(nonce);
+                if let Some(handle) = &self.handle {
+                    handle.stats.bytes_in.fetch_add(data.len() as u64, Ordering::Relaxed);
+                }
+                self.processor.message(data.clone());
+                self.event_sender.report(Event::Local {
+                    source: peer_pk.clone(),
+                    local: data,
+                });
+                true
+            },
+            Err(_) => {
+                self.event_sender
+                    .report(Event::Error(NodeError::MacMismatch(self.address)));
+                false
+            },
+        }
+    }
+
+    // the remote peer might change its address during the session
+    fn set_address(&mut self, address: SocketAddr) {
+        if self.address != address {
+            self.address = address;
+            if let Some(handle) = &self.handle {
+                *handle.stats.address.lock().unwrap() = address;
+            }
+        }
+    }
+
+    fn send_message(&self, number: u8, message: &[u8]) {
+        match split(&self.link, number, message) {
+            Ok(datagrams) => datagrams.for_each(|datagram| self.send_datagram(datagram)),
+            Err(error) => self
+                .event_sender
+                .report(Event::Error(NodeError::Fragment(self.address, error))),
+        }
+    }
+
+    fn send_datagram(&self, datagram: Datagram) {
+        if let Err(error) = self.socket.send_to(datagram.as_ref(), self.address) {
             self.event_sender
-                .report(Event::Info(format!("process connection with: {}", address)));
+                .report(Event::Error(NodeError::WriteTo(self.address, error)));
         }
     }
 }